@@ -0,0 +1,88 @@
+// expansion benchmarks, run with: cargo bench
+//
+// every case generates its input in memory, runs the release binary on it and
+// reports the best wall clock time of a few runs
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+
+/* ################################# Benchmark Cases ################################# */
+
+// the README's recursive \list macro over many items
+fn list_input(items: usize) -> String {
+    let mut input: String = String::from("\\def{list}{\\if{#}{#, \\list}{..., omega}}%\n\\list");
+    for i in 0..items {
+        input.push_str(&format!("{{item{}}}", i));
+    }
+    input.push_str("{}\n");
+    input
+}
+
+// the same pattern with a long macro body, so that every call puts a lot of text in front of the input
+fn long_body_input(items: usize, body_len: usize) -> String {
+    let padding: String = "lorem ipsum ".repeat(body_len / 12);
+    let mut input: String = format!("\\def{{list}}{{\\if{{#}}{{{} #, \\list}}{{end}}}}%\n\\list", padding);
+    for i in 0..items {
+        input.push_str(&format!("{{item{}}}", i));
+    }
+    input.push_str("{}\n");
+    input
+}
+
+// plain text without any macros, in lines of about 80 characters
+fn plain_text_input(bytes: usize) -> String {
+    let line: &str = "The quick brown fox jumps over the lazy dog, again and again and again.\n";
+    line.repeat(bytes / line.len())
+}
+
+// mostly plain text with an occasional macro call
+fn sparse_macro_input(bytes: usize) -> String {
+    let line: &str = "The quick brown fox jumps over the lazy dog, \\fox{again} and again.\n";
+    let mut input: String = String::from("\\def{fox}{[#]}%\n");
+    input.push_str(&line.repeat(bytes / line.len()));
+    input
+}
+
+/* ################################# Benchmark Functions ################################# */
+
+fn run(input: &str) -> Duration {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_macro_processor"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .expect("unable to start the macro processor");
+
+    let start: Instant = Instant::now();
+
+    let mut stdin = child.stdin.take().expect("unable to open stdin");
+    stdin.write_all(input.as_bytes()).expect("unable to write input");
+    drop(stdin);
+
+    let status = child.wait().expect("unable to wait for the macro processor");
+    let elapsed: Duration = start.elapsed();
+
+    assert!(status.success(), "the macro processor failed");
+    elapsed
+}
+
+fn bench(name: &str, input: &str) {
+    let best: Duration = (0..RUNS).map(|_| run(input)).min().unwrap();
+    let mib_per_sec: f64 = input.len() as f64 / best.as_secs_f64() / (1024.0 * 1024.0);
+
+    println!("{:<32} {:>10.2} ms {:>10.2} MiB/s", name, best.as_secs_f64() * 1000.0, mib_per_sec);
+}
+
+fn main() {
+    bench("list, 10k items", &list_input(10_000));
+    bench("list, 100k items", &list_input(100_000));
+    bench("list, 1M items", &list_input(1_000_000));
+    bench("long body list, 10k items", &long_body_input(10_000, 1_000));
+    bench("long body list, 50k items", &long_body_input(50_000, 1_000));
+    bench("plain text, 8 MiB", &plain_text_input(8 << 20));
+    bench("plain text, 64 MiB", &plain_text_input(64 << 20));
+    bench("sparse macros, 8 MiB", &sparse_macro_input(8 << 20));
+}
@@ -0,0 +1,135 @@
+// category codes of the special characters
+//
+// the role of a character (escape, group delimiter, parameter, comment, active) is looked
+// up here instead of being hard-coded, so both the comment stripper and the state
+// machine follow \catcode changes, only ASCII characters can be given a role
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/* ################################# Define Structs ################################# */
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Catcode {
+    Escape,
+    BeginGroup,
+    EndGroup,
+    Parameter,
+    Comment,
+    Other,
+    // expands to its body without an escape, set with \active only
+    Active,
+}
+
+#[derive(Clone)]
+pub struct Catcodes {
+    codes: [Catcode; 128],
+    // bodies of the active characters
+    active: HashMap<char, Rc<str>>,
+    // bytes that end a run of plain text in expanded text and in files
+    text_stops: Vec<u8>,
+    source_stops: Vec<u8>,
+    // bytes that end a run of a macro argument in expanded text and in files
+    argument_stops: Vec<u8>,
+    argument_source_stops: Vec<u8>,
+}
+
+/* ################################# Catcode Functions ################################# */
+
+impl Catcode {
+    pub fn parse(name: &str) -> Option<Catcode> {
+        match name {
+            "escape" | "0" => Some(Catcode::Escape),
+            "begingroup" | "1" => Some(Catcode::BeginGroup),
+            "endgroup" | "2" => Some(Catcode::EndGroup),
+            "parameter" | "6" => Some(Catcode::Parameter),
+            "comment" | "14" => Some(Catcode::Comment),
+            "other" | "12" => Some(Catcode::Other),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Catcodes {
+    fn default() -> Catcodes {
+        let mut catcodes: Catcodes = Catcodes {
+            codes: [Catcode::Other; 128],
+            active: HashMap::new(),
+            text_stops: Vec::new(),
+            source_stops: Vec::new(),
+            argument_stops: Vec::new(),
+            argument_source_stops: Vec::new(),
+        };
+
+        catcodes.set('\\', Catcode::Escape);
+        catcodes.set('{', Catcode::BeginGroup);
+        catcodes.set('}', Catcode::EndGroup);
+        catcodes.set('#', Catcode::Parameter);
+        catcodes.set('%', Catcode::Comment);
+        catcodes
+    }
+}
+
+impl Catcodes {
+    pub fn get(&self, c: char) -> Catcode {
+        if c.is_ascii() {
+            self.codes[c as usize]
+        } else {
+            Catcode::Other
+        }
+    }
+
+    // returns false for characters outside ASCII
+    pub fn set(&mut self, c: char, catcode: Catcode) -> bool {
+        if !c.is_ascii() {
+            return false;
+        }
+
+        self.codes[c as usize] = catcode;
+        self.active.remove(&c);
+
+        self.text_stops = self.bytes_with(&[Catcode::Escape, Catcode::Active]);
+        self.source_stops = self.bytes_with(&[Catcode::Escape, Catcode::Comment, Catcode::Active]);
+        self.argument_stops = self.bytes_with(&[Catcode::Escape, Catcode::BeginGroup, Catcode::EndGroup]);
+        self.argument_source_stops = self.bytes_with(&[Catcode::Escape, Catcode::BeginGroup, Catcode::EndGroup, Catcode::Comment]);
+        true
+    }
+
+    // returns false for characters outside ASCII
+    pub fn set_active(&mut self, c: char, body: Rc<str>) -> bool {
+        if !self.set(c, Catcode::Active) {
+            return false;
+        }
+
+        self.active.insert(c, body);
+        true
+    }
+
+    pub fn active_body(&self, c: char) -> &Rc<str> {
+        &self.active[&c]
+    }
+
+    // bytes the plain text fast path stops at in expanded text
+    pub fn text_stops(&self) -> &[u8] {
+        &self.text_stops
+    }
+
+    // bytes the plain text fast path stops at in files, where comments still need to be stripped
+    pub fn source_stops(&self) -> &[u8] {
+        &self.source_stops
+    }
+
+    // bytes the argument fast path stops at in expanded text
+    pub fn argument_stops(&self) -> &[u8] {
+        &self.argument_stops
+    }
+
+    // bytes the argument fast path stops at in files
+    pub fn argument_source_stops(&self) -> &[u8] {
+        &self.argument_source_stops
+    }
+
+    fn bytes_with(&self, catcodes: &[Catcode]) -> Vec<u8> {
+        (0..128u8).filter(|&byte| catcodes.contains(&self.codes[byte as usize])).collect()
+    }
+}
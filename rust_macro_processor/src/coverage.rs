@@ -0,0 +1,428 @@
+// definition and branch coverage for --coverage
+//
+// every \def is a definition site and every \if or \ifdef a conditional site, the
+// conditionals of a macro body are registered with the definition, at the line the
+// \def text was read from, so the ones that are never reached are reported as well,
+// a conditional is told apart from the others by its offset in the body, conditionals
+// read from a file directly or from a text that is not a body by their file and line
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::rc::Rc;
+use std::str::CharIndices;
+
+use crate::catcode::{Catcode, Catcodes};
+
+/* ################################# Define Structs ################################# */
+
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum Format {
+    #[default]
+    Text,
+    Lcov,
+}
+
+// file name and line
+type Site = (String, usize);
+
+struct Definition {
+    name: String,
+    site: Site,
+    calls: u64,
+    // kept so that the address of the body identifies it as long as the coverage runs
+    body: Rc<str>,
+}
+
+// a call whose result is still being read, like the calls of the profiler
+struct Call {
+    level: usize,
+    base: Option<usize>,
+    // the definition site of a macro call, the text it returned maps there
+    site: Option<Site>,
+    // the conditional of an \if or \ifdef call
+    conditional: Option<usize>,
+}
+
+struct Conditional {
+    // \if or \ifdef
+    name: String,
+    site: Site,
+    // the macro whose body the conditional is in
+    owner: Option<String>,
+    then_taken: u64,
+    else_taken: u64,
+}
+
+// the sites of one file, for the lcov report
+#[derive(Default)]
+struct FileSites<'a> {
+    definitions: Vec<&'a Definition>,
+    conditionals: Vec<&'a Conditional>,
+}
+
+#[derive(Default)]
+pub struct Coverage {
+    definitions: Vec<Definition>,
+    // the definition each macro name currently has
+    current: HashMap<String, usize>,
+    // the definition of each body by its address
+    bodies: HashMap<*const u8, usize>,
+    conditionals: Vec<Conditional>,
+    // the conditionals of the bodies, by definition and offset of the name in the body
+    body_conditionals: HashMap<(usize, usize), usize>,
+    // the other conditionals, by site and name
+    site_conditionals: HashMap<(Site, String), usize>,
+    stack: Vec<Call>,
+    // the site of the call being read
+    call_site: Option<Site>,
+    // the text and offset of the character after the last escape character read
+    escape_origin: Option<(*const u8, usize)>,
+    // the file and line of the escape characters of the \def text being read, by offset
+    body_lines: HashMap<usize, Site>,
+}
+
+/* ################################# Coverage Functions ################################# */
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "lcov" => Some(Format::Lcov),
+            _ => None,
+        }
+    }
+}
+
+impl Coverage {
+    // an escape character has been read, origin is the text and offset it was read from unless it came from a file
+    pub fn escape_read(&mut self, origin: Option<(&Rc<str>, usize)>) {
+        self.escape_origin = origin.map(|(text, start)| (text.as_ptr(), start));
+    }
+
+    // an escape character of the \def text being read, offset is that of the character after it, position
+    // the file and line it was read from unless it came from a pushed text
+    pub fn body_escape(&mut self, offset: usize, position: Option<(&str, usize)>) {
+        if let Some((file, line)) = position {
+            self.body_lines.insert(offset, (file.to_string(), line));
+        }
+    }
+
+    // definition is the name of the macro for calls of defined macros, conditional the name of an \if or \ifdef call,
+    // position is where the input is being read
+    pub fn enter(&mut self, definition: Option<&str>, conditional: Option<&str>, position: Option<(&str, usize)>, level: usize) {
+        let body_site: Option<&Site> = self.stack.iter().rev().find_map(|call| call.site.as_ref());
+        self.call_site = match body_site {
+            Some(site) => Some(site.clone()),
+            None => position.map(|(file, line)| (file.to_string(), line)),
+        };
+
+        let conditional: Option<usize> = conditional.and_then(|name| self.find_conditional(name));
+        let site: Option<Site> = definition.and_then(|name| self.count_call(name));
+        self.stack.push(Call { level, base: None, site, conditional });
+    }
+
+    // the conditional at the escape character read last, registered with its body or else by its site
+    fn find_conditional(&mut self, name: &str) -> Option<usize> {
+        let in_body: Option<&usize> = self.escape_origin.and_then(|(body, offset)| {
+            let definition: usize = *self.bodies.get(&body)?;
+            self.body_conditionals.get(&(definition, offset))
+        });
+        if let Some(&index) = in_body {
+            return Some(index);
+        }
+
+        let site: Site = self.call_site.clone()?;
+        let index: usize = match self.site_conditionals.get(&(site.clone(), name.to_string())) {
+            Some(&index) => index,
+            None => {
+                self.conditionals.push(Conditional {
+                    name: name.to_string(),
+                    site: site.clone(),
+                    owner: None,
+                    then_taken: 0,
+                    else_taken: 0,
+                });
+                self.site_conditionals.insert((site, name.to_string()), self.conditionals.len() - 1);
+                self.conditionals.len() - 1
+            }
+        };
+        Some(index)
+    }
+
+    // the call being read had to autoload its macro, which is defined now
+    pub fn autoloaded(&mut self, name: &str) {
+        let site: Option<Site> = self.count_call(name);
+        if let Some(call) = self.stack.last_mut() {
+            call.site = site;
+        }
+    }
+
+    fn count_call(&mut self, name: &str) -> Option<Site> {
+        let index: usize = *self.current.get(name)?;
+        self.definitions[index].calls += 1;
+        Some(self.definitions[index].site.clone())
+    }
+
+    pub fn returned(&mut self, chunks: usize) {
+        if let Some(call) = self.stack.last_mut() {
+            call.base = Some(chunks);
+        }
+    }
+
+    // the nested expansion at level + 1 goes on at level, see Session::join_caller
+    pub fn join_caller(&mut self, chunks: usize, level: usize) {
+        if let Some(call) = self.stack.iter_mut().rev().find(|call| call.level == level) {
+            call.base.get_or_insert(chunks);
+        }
+        for call in self.stack.iter_mut().filter(|call| call.level > level) {
+            call.level = level;
+        }
+    }
+
+    pub fn leave(&mut self, chunks: usize, level: usize) {
+        while let Some(call) = self.stack.last() {
+            if call.level != level || call.base.is_none_or(|base| chunks > base) {
+                break;
+            }
+            self.stack.pop();
+        }
+    }
+
+    // the \def being read has defined name with body, its conditionals are registered along with it
+    pub fn define(&mut self, name: &str, body: &Rc<str>, catcodes: &Catcodes) {
+        let body_lines: HashMap<usize, Site> = std::mem::take(&mut self.body_lines);
+        let site: Site = match self.call_site.clone() {
+            Some(site) => site,
+            None => return,
+        };
+
+        let definition: usize = self.definitions.len();
+        for (offset, conditional) in conditionals_in(body, catcodes) {
+            self.conditionals.push(Conditional {
+                // the offset is that of the name, right after the escape character
+                name: format!("{}{}", body[..offset].chars().next_back().unwrap_or('\\'), conditional),
+                site: body_lines.get(&offset).cloned().unwrap_or_else(|| site.clone()),
+                owner: Some(name.to_string()),
+                then_taken: 0,
+                else_taken: 0,
+            });
+            self.body_conditionals.insert((definition, offset), self.conditionals.len() - 1);
+        }
+
+        self.definitions.push(Definition {
+            name: name.to_string(),
+            site,
+            calls: 0,
+            body: Rc::clone(body),
+        });
+        self.bodies.insert(self.definitions[definition].body.as_ptr(), definition);
+        self.current.insert(name.to_string(), definition);
+    }
+
+    pub fn undefine(&mut self, name: &str) {
+        self.current.remove(name);
+    }
+
+    // the \if or \ifdef being read takes a branch
+    pub fn branch(&mut self, then_taken: bool) {
+        if let Some(index) = self.stack.last().and_then(|call| call.conditional) {
+            let conditional: &mut Conditional = &mut self.conditionals[index];
+            if then_taken {
+                conditional.then_taken += 1;
+            } else {
+                conditional.else_taken += 1;
+            }
+        }
+    }
+
+    pub fn report(&self, format: Format) -> String {
+        match format {
+            Format::Text => self.text_report(),
+            Format::Lcov => self.lcov_report(),
+        }
+    }
+
+    fn text_report(&self) -> String {
+        let mut report: String = String::new();
+
+        let mut definitions: Vec<&Definition> = self.definitions.iter().collect();
+        definitions.sort_by(|a, b| a.site.cmp(&b.site));
+        let called: usize = definitions.iter().filter(|definition| definition.calls > 0).count();
+
+        let _ = writeln!(report, "Definitions: {} of {} called", called, definitions.len());
+        for definition in definitions {
+            let calls: String = match definition.calls {
+                0 => String::from("never called"),
+                1 => String::from("called once"),
+                calls => format!("called {} times", calls),
+            };
+            let _ = writeln!(report, "  {}:{}  \\{}  {}", definition.site.0, definition.site.1, definition.name, calls);
+        }
+
+        // the conditionals of a line stay in the order they were registered in
+        let mut conditionals: Vec<&Conditional> = self.conditionals.iter().collect();
+        conditionals.sort_by(|a, b| a.site.cmp(&b.site));
+        let taken: usize = conditionals.iter().map(|conditional| taken_branches(conditional)).sum();
+
+        let _ = writeln!(report, "Branches: {} of {} taken", taken, 2 * conditionals.len());
+        for conditional in conditionals {
+            let owner: String = match &conditional.owner {
+                Some(owner) => format!(" in \\{}", owner),
+                None => String::new(),
+            };
+            let missing: &str = match (conditional.then_taken, conditional.else_taken) {
+                (0, 0) => "  (never reached)",
+                (0, _) => "  (then never taken)",
+                (_, 0) => "  (else never taken)",
+                _ => "",
+            };
+            let _ = writeln!(report, "  {}:{}  {}{}  then {}, else {}{}", conditional.site.0, conditional.site.1, conditional.name, owner, conditional.then_taken, conditional.else_taken, missing);
+        }
+
+        report
+    }
+
+    // one record per file, definitions as functions and conditionals as branch pairs
+    fn lcov_report(&self) -> String {
+        let mut files: BTreeMap<&str, FileSites> = BTreeMap::new();
+        for definition in &self.definitions {
+            files.entry(&definition.site.0).or_default().definitions.push(definition);
+        }
+        for conditional in &self.conditionals {
+            files.entry(&conditional.site.0).or_default().conditionals.push(conditional);
+        }
+
+        let mut report: String = String::new();
+
+        for (file, mut sites) in files {
+            sites.definitions.sort_by_key(|definition| definition.site.1);
+            sites.conditionals.sort_by_key(|conditional| conditional.site.1);
+
+            // the hits of a line are the calls of its definitions and the evaluations of its conditionals
+            let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+
+            let _ = writeln!(report, "TN:");
+            let _ = writeln!(report, "SF:{}", file);
+
+            for definition in &sites.definitions {
+                let _ = writeln!(report, "FN:{},{}", definition.site.1, definition.name);
+            }
+            for definition in &sites.definitions {
+                let _ = writeln!(report, "FNDA:{},{}", definition.calls, definition.name);
+                *lines.entry(definition.site.1).or_default() += definition.calls;
+            }
+            let _ = writeln!(report, "FNF:{}", sites.definitions.len());
+            let _ = writeln!(report, "FNH:{}", sites.definitions.iter().filter(|definition| definition.calls > 0).count());
+
+            // the conditionals of one line are numbered as its blocks
+            let mut branches_hit: usize = 0;
+            let mut blocks: HashMap<usize, usize> = HashMap::new();
+            for conditional in &sites.conditionals {
+                let line: usize = conditional.site.1;
+                let block: &mut usize = blocks.entry(line).or_default();
+                let _ = writeln!(report, "BRDA:{},{},0,{}", line, block, conditional.then_taken);
+                let _ = writeln!(report, "BRDA:{},{},1,{}", line, block, conditional.else_taken);
+                *block += 1;
+                branches_hit += taken_branches(conditional);
+                *lines.entry(line).or_default() += conditional.then_taken + conditional.else_taken;
+            }
+            let _ = writeln!(report, "BRF:{}", 2 * sites.conditionals.len());
+            let _ = writeln!(report, "BRH:{}", branches_hit);
+
+            for (line, hits) in &lines {
+                let _ = writeln!(report, "DA:{},{}", line, hits);
+            }
+            let _ = writeln!(report, "LF:{}", lines.len());
+            let _ = writeln!(report, "LH:{}", lines.values().filter(|&&hits| hits > 0).count());
+            let _ = writeln!(report, "end_of_record");
+        }
+
+        report
+    }
+}
+
+fn taken_branches(conditional: &Conditional) -> usize {
+    (conditional.then_taken > 0) as usize + (conditional.else_taken > 0) as usize
+}
+
+// the \if and \ifdef calls of a body with the offset of the character after their escape character,
+// leaving out those in the \def calls of the body, which belong to the macros these define
+fn conditionals_in<'a>(body: &'a str, catcodes: &Catcodes) -> Vec<(usize, &'a str)> {
+    let mut conditionals: Vec<(usize, &'a str)> = Vec::new();
+    let mut depth: usize = 0;
+    // the depth of a nested \def and the number of its arguments still to be closed
+    let mut nested_def: Option<(usize, usize)> = None;
+    let mut chars: CharIndices = body.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match catcodes.get(c) {
+            Catcode::Escape => {
+                let start: usize = i + c.len_utf8();
+                let name_end: usize = body[start..].find(|c: char| !c.is_alphanumeric()).map_or(body.len(), |end| start + end);
+                let opens: bool = body[name_end..].chars().next().is_some_and(|c| catcodes.get(c) == Catcode::BeginGroup);
+
+                match &body[start..name_end] {
+                    "if" | "ifdef" if opens && nested_def.is_none() => conditionals.push((start, &body[start..name_end])),
+                    "def" if opens && nested_def.is_none() => nested_def = Some((depth, 2)),
+                    // an escaped character is never special
+                    "" => {
+                        chars.next();
+                    }
+                    _ => {}
+                }
+            }
+            Catcode::BeginGroup => depth += 1,
+            Catcode::EndGroup => {
+                depth = depth.saturating_sub(1);
+                if let Some((def_depth, arguments)) = nested_def {
+                    if depth == def_depth {
+                        nested_def = if arguments == 1 { None } else { Some((def_depth, arguments - 1)) };
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    conditionals
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_conditionals() {
+        let catcodes: Catcodes = Catcodes::default();
+
+        let body: &str = "a\\if{#}{x}{\\ifdef{b}{y}{z}}";
+        assert_eq!(conditionals_in(body, &catcodes), vec![(2, "if"), (12, "ifdef")]);
+
+        // escaped backslashes, other macros and names without an argument are not conditionals
+        assert_eq!(conditionals_in("\\\\if{a}\\iffy{b}\\if x", &catcodes), vec![]);
+        assert_eq!(conditionals_in("é\\if{a}{b}{c}", &catcodes), vec![(3, "if")]);
+    }
+
+    #[test]
+    fn skips_nested_definitions() {
+        let catcodes: Catcodes = Catcodes::default();
+
+        // the conditionals of \inner belong to it, the one after its definition to the body
+        let body: &str = "\\def{inner}{\\if{a}{{b}}{c}}\\if{d}{e}{f}";
+        assert_eq!(conditionals_in(body, &catcodes), vec![(28, "if")]);
+
+        let body: &str = "\\def{\\if}{x}\\ifdef{y}{}{}";
+        assert_eq!(conditionals_in(body, &catcodes), vec![(13, "ifdef")]);
+    }
+
+    #[test]
+    fn follows_catcodes() {
+        let mut catcodes: Catcodes = Catcodes::default();
+        catcodes.set('\\', Catcode::Other);
+        catcodes.set('!', Catcode::Escape);
+
+        assert_eq!(conditionals_in("\\if{a}{b}{c}!if{a}{b}{c}", &catcodes), vec![(13, "if")]);
+    }
+}
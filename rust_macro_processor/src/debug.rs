@@ -0,0 +1,238 @@
+// step debugger for --debug
+//
+// the expansion stops before macro calls, before every call while stepping and
+// otherwise only at breakpoints, commands are read from stdin and everything the
+// debugger prints goes to stderr so that it does not mix with the output
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::input::Input;
+use crate::macros::MacroTable;
+use crate::{current_position, dump_macros, source_position, Frame, Session};
+
+/* ################################# Define Structs ################################# */
+
+const HELP: &str = "\
+  step, s                   run to the next macro call
+  continue, c               run to the next breakpoint
+  break, b NAME|FILE:LINE   stop before calls of NAME, or at the first call on a line
+  delete, d NAME|FILE:LINE  remove a breakpoint
+  breakpoints               list the breakpoints
+  input, i                  print the pending input
+  output, o                 print the end of the output so far
+  macros, m                 print the macro table
+  quit, q                   stop the expansion
+  help, h                   print this help
+";
+
+// bytes of pending input printed per nesting level
+const PREVIEW_SIZE: usize = 400;
+
+// bytes of the output handed to the sink that are kept for the output command
+const OUTPUT_TAIL_SIZE: usize = 64 * 1024;
+
+#[derive(PartialEq)]
+enum Breakpoint {
+    Macro(String),
+    Line(String, usize),
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    // stop before the next call whether it has a breakpoint or not
+    stepping: bool,
+    // a line breakpoint only stops at the first call on its line
+    last_line: Option<(String, usize)>,
+    // the end of the output handed to the sink so far, between one and two OUTPUT_TAIL_SIZE once it is cut
+    written: String,
+    // the start of the output was dropped from written
+    truncated: bool,
+}
+
+/* ################################# Debugger Functions ################################# */
+
+impl Debugger {
+    // the debugger stops before the first call, so that breakpoints can be set
+    pub fn new() -> Debugger {
+        Debugger {
+            stepping: true,
+            ..Debugger::default()
+        }
+    }
+
+    pub fn record_output(&mut self, text: &str) {
+        self.written.push_str(text);
+
+        // the start is dropped once the tail has doubled, so that each byte is moved at most once
+        if self.written.len() > 2 * OUTPUT_TAIL_SIZE {
+            let cut: usize = tail_start(&self.written, OUTPUT_TAIL_SIZE);
+            self.written.drain(..cut);
+            self.truncated = true;
+        }
+    }
+
+    // the last OUTPUT_TAIL_SIZE bytes of the output written followed by the pending output,
+    // starting with "..." when the start is left out
+    fn output_tail(&self, pending: &str) -> String {
+        let pending_tail: &str = &pending[tail_start(pending, OUTPUT_TAIL_SIZE)..];
+        let written_tail: &str = &self.written[tail_start(&self.written, OUTPUT_TAIL_SIZE - pending_tail.len())..];
+
+        let complete: bool = !self.truncated && written_tail.len() == self.written.len() && pending_tail.len() == pending.len();
+        let dropped: &str = if complete { "" } else { "..." };
+        format!("{}{}{}", dropped, written_tail, pending_tail)
+    }
+}
+
+// the start of the last size bytes of the text, moved forward to a character boundary
+fn tail_start(text: &str, size: usize) -> usize {
+    let mut start: usize = text.len().saturating_sub(size);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    start
+}
+
+impl Breakpoint {
+    fn parse(text: &str) -> Option<Breakpoint> {
+        match text.rsplit_once(':') {
+            Some((file, line)) if !file.is_empty() => match line.parse::<usize>() {
+                Ok(line) => Some(Breakpoint::Line(file.to_string(), line)),
+                Err(_) => None,
+            },
+            _ if !text.is_empty() => Some(Breakpoint::Macro(text.to_string())),
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Breakpoint::Macro(name) => name.clone(),
+            Breakpoint::Line(file, line) => format!("{}:{}", file, line),
+        }
+    }
+}
+
+// called before each macro call, escape is the escape character of the call or None for an active character
+pub fn pause(name: &str, escape: Option<char>, input: &Input, suspended: &[Frame], output: &str, macro_table: &MacroTable, session: &mut Session) {
+    let depth: usize = session.depth;
+    let debugger: &mut Debugger = match &mut session.debugger {
+        Some(debugger) => debugger,
+        None => return,
+    };
+
+    let position: Option<(String, usize)> = current_position(input, suspended).map(|(file, line)| (file.to_string(), line));
+    let new_line: bool = position != debugger.last_line;
+
+    let hit: bool = debugger.stepping
+        || debugger.breakpoints.iter().any(|breakpoint| match (breakpoint, &position) {
+            (Breakpoint::Macro(macro_name), _) => macro_name == name,
+            (Breakpoint::Line(file, line), Some((current_file, current_line))) => new_line && line == current_line && Path::new(current_file).ends_with(file),
+            (Breakpoint::Line(..), None) => false,
+        });
+    debugger.last_line = position;
+
+    if !hit {
+        return;
+    }
+
+    let call: String = match escape {
+        Some(escape) => format!("{}{}", escape, name),
+        None => name.to_string(),
+    };
+    eprintln!("Stopped before {} at {} depth {}", call, source_position(input, suspended), depth);
+
+    loop {
+        eprint!("(debug) ");
+        let _ = io::stderr().flush();
+
+        // the end of the commands lets the expansion run to the end
+        let mut line: String = String::new();
+        if !matches!(io::stdin().read_line(&mut line), Ok(read) if read > 0) {
+            eprintln!();
+            debugger.stepping = false;
+            debugger.breakpoints.clear();
+            return;
+        }
+
+        let (command, argument): (&str, &str) = match line.trim().split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line.trim(), ""),
+        };
+
+        match (command, argument) {
+            ("step" | "s", "") => {
+                debugger.stepping = true;
+                return;
+            }
+            ("continue" | "c", "") => {
+                debugger.stepping = false;
+                return;
+            }
+            ("break" | "b", breakpoint) => match Breakpoint::parse(breakpoint) {
+                Some(breakpoint) if debugger.breakpoints.contains(&breakpoint) => eprintln!("Breakpoint {} is already set", breakpoint.describe()),
+                Some(breakpoint) => debugger.breakpoints.push(breakpoint),
+                None => eprintln!("Expected a macro name or FILE:LINE"),
+            },
+            ("delete" | "d", breakpoint) => match Breakpoint::parse(breakpoint) {
+                Some(breakpoint) if debugger.breakpoints.contains(&breakpoint) => debugger.breakpoints.retain(|set| *set != breakpoint),
+                Some(breakpoint) => eprintln!("No breakpoint {}", breakpoint.describe()),
+                None => eprintln!("Expected a macro name or FILE:LINE"),
+            },
+            ("breakpoints", "") => {
+                for breakpoint in &debugger.breakpoints {
+                    eprintln!("{}", breakpoint.describe());
+                }
+            }
+            ("input" | "i", "") => {
+                eprintln!("{}", input.preview(PREVIEW_SIZE));
+
+                // the callers waiting for a nested expansion, innermost first
+                for (level, frame) in suspended.iter().enumerate().rev() {
+                    eprintln!("-- then at depth {}:", level);
+                    eprintln!("{}", frame.input.preview(PREVIEW_SIZE));
+                }
+            }
+            ("output" | "o", "") => {
+                // a nested expansion collects its own output, the outermost one is the document's
+                let pending: &str = suspended.first().map_or(output, |frame| &frame.output);
+                eprintln!("{}", debugger.output_tail(pending));
+            }
+            ("macros" | "m", "") => dump_macros(macro_table),
+            ("quit" | "q", "") => {
+                die!("Expansion stopped in the debugger");
+            }
+            ("help" | "h", "") => eprint!("{}", HELP),
+            ("", "") => {}
+            _ => eprintln!("Unknown command {}, try help", line.trim()),
+        }
+    }
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_tail_is_cut_to_size() {
+        let mut debugger: Debugger = Debugger::new();
+        debugger.record_output("abc");
+        assert_eq!(debugger.output_tail("def"), "abcdef");
+
+        // the pending output alone can exceed the size
+        let pending: String = "é".repeat(OUTPUT_TAIL_SIZE);
+        let tail: String = debugger.output_tail(&pending);
+        assert_eq!(tail, format!("...{}", "é".repeat(OUTPUT_TAIL_SIZE / 2)));
+
+        // as can both together, the written output is cut first
+        let pending: String = "y".repeat(OUTPUT_TAIL_SIZE - 2);
+        assert_eq!(debugger.output_tail(&pending), format!("...bc{}", pending));
+
+        // and once the written output has been cut it is marked even if the rest fits
+        debugger.record_output(&"x".repeat(2 * OUTPUT_TAIL_SIZE));
+        assert_eq!(debugger.output_tail(""), format!("...{}", "x".repeat(OUTPUT_TAIL_SIZE)));
+    }
+}
@@ -0,0 +1,289 @@
+// definition files for --defs
+//
+// a definition file either holds NAME=VALUE lines or a single JSON object whose
+// members are the definitions, strings are taken as is, numbers, booleans and
+// null as their JSON text (null as an empty value)
+
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/* ################################# Definition File Functions ################################# */
+
+// returns the definitions of the file in file order, or the message for an invalid file
+pub fn parse(text: &str, name: &str) -> Result<Vec<(String, String)>, String> {
+    let text: &str = text.strip_prefix('\u{FEFF}').unwrap_or(text);
+
+    if text.trim_start().starts_with('{') {
+        parse_json(text, name)
+    } else {
+        parse_lines(text, name)
+    }
+}
+
+// NAME=VALUE per line, blank lines and lines starting with '%' are skipped, white space
+// around the name and the value is dropped
+fn parse_lines(text: &str, name: &str) -> Result<Vec<(String, String)>, String> {
+    let mut definitions: Vec<(String, String)> = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line: &str = line.strip_suffix('\r').unwrap_or(line);
+        if line.trim().is_empty() || line.trim_start().starts_with('%') {
+            continue;
+        }
+
+        match line.split_once('=') {
+            Some((key, value)) => definitions.push((key.trim().to_string(), value.trim().to_string())),
+            None => return Err(format!("Invalid definition on line {} of {}", number + 1, name)),
+        }
+    }
+
+    Ok(definitions)
+}
+
+struct JsonParser<'a> {
+    text: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    name: &'a str,
+}
+
+fn parse_json(text: &str, name: &str) -> Result<Vec<(String, String)>, String> {
+    let mut parser: JsonParser = JsonParser {
+        text,
+        chars: text.char_indices().peekable(),
+        name,
+    };
+    let mut definitions: Vec<(String, String)> = Vec::new();
+
+    parser.expect('{')?;
+    if parser.peek() == Some('}') {
+        parser.next();
+    } else {
+        loop {
+            let key: String = parser.string()?;
+            parser.expect(':')?;
+            let value: String = parser.value()?;
+            definitions.push((key, value));
+
+            match parser.next() {
+                Some(',') => {}
+                Some('}') => break,
+                _ => return Err(parser.error()),
+            }
+        }
+    }
+
+    if parser.peek().is_some() {
+        return Err(parser.error());
+    }
+
+    Ok(definitions)
+}
+
+impl JsonParser<'_> {
+    // the next character that is not white space
+    fn peek(&mut self) -> Option<char> {
+        while let Some(&(_, c)) = self.chars.peek() {
+            if !matches!(c, ' ' | '\t' | '\n' | '\r') {
+                return Some(c);
+            }
+            self.chars.next();
+        }
+        None
+    }
+
+    fn next(&mut self) -> Option<char> {
+        self.peek()?;
+        self.chars.next().map(|(_, c)| c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        if self.next() != Some(expected) {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    fn offset(&mut self) -> usize {
+        self.chars.peek().map_or(self.text.len(), |&(i, _)| i)
+    }
+
+    // the message for the invalid JSON at the current position
+    fn error(&mut self) -> String {
+        let offset: usize = self.offset();
+        format!("Invalid JSON in {} at byte {}", self.name, offset)
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some('"') => self.string(),
+            Some(c) if c == '-' || c.is_ascii_alphanumeric() => {
+                let start: usize = self.offset();
+                while matches!(self.chars.peek(), Some(&(_, c)) if c == '-' || c == '+' || c == '.' || c.is_ascii_alphanumeric()) {
+                    self.chars.next();
+                }
+                let end: usize = self.offset();
+
+                match &self.text[start..end] {
+                    "null" => Ok(String::new()),
+                    "true" | "false" => Ok(self.text[start..end].to_string()),
+                    number if is_number(number) => Ok(number.to_string()),
+                    _ => Err(self.error()),
+                }
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string: String = String::new();
+
+        loop {
+            let c: char = match self.chars.next() {
+                Some((_, c)) => c,
+                None => return Err(self.error()),
+            };
+
+            match c {
+                '"' => return Ok(string),
+                '\\' => {
+                    let escaped: char = match self.chars.next() {
+                        Some((_, escaped)) => escaped,
+                        None => return Err(self.error()),
+                    };
+
+                    match escaped {
+                        '"' | '\\' | '/' => string.push(escaped),
+                        'b' => string.push('\u{8}'),
+                        'f' => string.push('\u{C}'),
+                        'n' => string.push('\n'),
+                        'r' => string.push('\r'),
+                        't' => string.push('\t'),
+                        'u' => {
+                            let unit: u32 = self.hex4()?;
+
+                            // characters outside the basic plane are written as surrogate pairs
+                            let code: u32 = if (0xD800..0xDC00).contains(&unit) {
+                                if self.chars.next().map(|(_, c)| c) != Some('\\') || self.chars.next().map(|(_, c)| c) != Some('u') {
+                                    return Err(self.error());
+                                }
+                                let low: u32 = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(self.error());
+                                }
+                                0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                            } else {
+                                unit
+                            };
+
+                            match char::from_u32(code) {
+                                Some(c) => string.push(c),
+                                None => return Err(self.error()),
+                            }
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+                c if (c as u32) < 0x20 => return Err(self.error()),
+                c => string.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut unit: u32 = 0;
+
+        for _ in 0..4 {
+            match self.chars.next().and_then(|(_, c)| c.to_digit(16)) {
+                Some(digit) => unit = unit * 16 + digit,
+                None => return Err(self.error()),
+            }
+        }
+
+        Ok(unit)
+    }
+}
+
+// the JSON number grammar: an optional minus, an integer part without leading zeros,
+// an optional fraction and an optional exponent
+fn is_number(text: &str) -> bool {
+    let bytes: &[u8] = text.strip_prefix('-').unwrap_or(text).as_bytes();
+    let digits = |from: usize| bytes[from..].iter().take_while(|byte| byte.is_ascii_digit()).count();
+
+    let mut pos: usize = match digits(0) {
+        0 => return false,
+        count if count > 1 && bytes[0] == b'0' => return false,
+        count => count,
+    };
+
+    if bytes.get(pos) == Some(&b'.') {
+        match digits(pos + 1) {
+            0 => return false,
+            count => pos += 1 + count,
+        }
+    }
+
+    if matches!(bytes.get(pos), Some(b'e' | b'E')) {
+        pos += 1;
+        if matches!(bytes.get(pos), Some(b'+' | b'-')) {
+            pos += 1;
+        }
+        match digits(pos) {
+            0 => return false,
+            count => pos += count,
+        }
+    }
+
+    pos == bytes.len()
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(definitions: &[(&str, &str)]) -> Result<Vec<(String, String)>, String> {
+        Ok(definitions.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect())
+    }
+
+    fn rejects(text: &str) -> bool {
+        parse_json(text, "test.json").is_err()
+    }
+
+    #[test]
+    fn json_values() {
+        let text: &str = r#" { "a": "x\ty", "b": 12, "c": -0.5e+3, "d": true, "e": null, "f": "é😀" } "#;
+        assert_eq!(parse_json(text, "test.json"), pairs(&[("a", "x\ty"), ("b", "12"), ("c", "-0.5e+3"), ("d", "true"), ("e", ""), ("f", "é😀")]));
+        assert_eq!(parse_json("{}", "test.json"), pairs(&[]));
+    }
+
+    #[test]
+    fn json_numbers() {
+        for number in ["0", "-0", "10", "1.25", "1e9", "2E-3", "-7.5e+10"] {
+            assert_eq!(parse_json(&format!(r#"{{"n": {}}}"#, number), "test.json"), pairs(&[("n", number)]), "{}", number);
+        }
+        for number in ["01", "-01", "+1", "1.", ".5", "1e", "1e+", "-", "Infinity", "-Infinity", "nan", "NaN", "inf", "0x10"] {
+            assert!(rejects(&format!(r#"{{"n": {}}}"#, number)), "{}", number);
+        }
+    }
+
+    #[test]
+    fn json_errors() {
+        assert!(rejects(r#"{"a": "x""#));
+        assert!(rejects(r#"{"a": "x",}"#));
+        assert!(rejects(r#"{"a": "x"} {}"#));
+        assert!(rejects(r#"{"a": 'x'}"#));
+        assert!(rejects("{\"a\": \"line\nbreak\"}"));
+        assert!(rejects(r#"{"a": "\ud83d"}"#));
+        assert!(rejects(r#"{"a": "\q"}"#));
+        assert_eq!(parse_json(r#"{"a" "x"}"#, "test.json"), Err("Invalid JSON in test.json at byte 6".to_string()));
+    }
+
+    #[test]
+    fn lines() {
+        let text: &str = "% comment\nA=one\n\n B = two \r\nC=\nD=x=y\n";
+        assert_eq!(parse(text, "test.defs"), pairs(&[("A", "one"), ("B", "two"), ("C", ""), ("D", "x=y")]));
+        assert_eq!(parse("A=one\nB\n", "test.defs"), Err("Invalid definition on line 2 of test.defs".to_string()));
+    }
+}
@@ -0,0 +1,94 @@
+// make dependency rules for -M and -MD
+//
+// the rule names the output as the target and every file that was read while
+// expanding it as a prerequisite, in the order the files were first opened
+
+use std::path::PathBuf;
+
+/* ################################# Define Structs ################################# */
+
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum Dependencies {
+    #[default]
+    Off,
+    // -M, the rule replaces the output
+    Only,
+    // -MD, the rule is written next to the output
+    WithOutput,
+}
+
+// rule lines are continued with a backslash before they get longer than this
+const LINE_WIDTH: usize = 75;
+
+/* ################################# Dependency Functions ################################# */
+
+pub fn make_rule(target: &str, prerequisites: &[PathBuf]) -> String {
+    let mut rule: String = escape(target);
+    rule.push(':');
+    let mut line_length: usize = rule.len();
+
+    for prerequisite in prerequisites {
+        let name: String = escape(&prerequisite.to_string_lossy());
+
+        if line_length + 1 + name.len() > LINE_WIDTH {
+            rule.push_str(" \\\n");
+            line_length = 0;
+        }
+
+        rule.push(' ');
+        rule.push_str(&name);
+        line_length += 1 + name.len();
+    }
+
+    rule.push('\n');
+    rule
+}
+
+// spaces and '#' are escaped with a backslash and '$' is doubled, as make expects
+fn escape(name: &str) -> String {
+    let mut escaped: String = String::with_capacity(name.len());
+
+    for c in name.chars() {
+        match c {
+            ' ' | '#' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '$' => escaped.push_str("$$"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(escape("plain/file.tex"), "plain/file.tex");
+        assert_eq!(escape("my file.tex"), "my\\ file.tex");
+        assert_eq!(escape("#1.tex"), "\\#1.tex");
+        assert_eq!(escape("$HOME.tex"), "$$HOME.tex");
+        assert_eq!(escape("a b#c$d"), "a\\ b\\#c$$d");
+    }
+
+    #[test]
+    fn rules() {
+        let prerequisites: Vec<PathBuf> = vec![PathBuf::from("in.tex"), PathBuf::from("my lib.tex")];
+        assert_eq!(make_rule("out.txt", &prerequisites), "out.txt: in.tex my\\ lib.tex\n");
+        assert_eq!(make_rule("out.txt", &[]), "out.txt:\n");
+
+        // long rules are continued on the next line
+        let long: Vec<PathBuf> = (0..10).map(|i| PathBuf::from(format!("chapter_{:02}.tex", i))).collect();
+        let rule: String = make_rule("book.txt", &long);
+        assert!(rule.lines().all(|line| line.len() <= LINE_WIDTH + 2));
+        assert_eq!(rule.matches(" \\\n").count(), 2);
+        let names: Vec<String> = long.iter().map(|path| path.to_string_lossy().into_owned()).collect();
+        assert_eq!(rule.replace(" \\\n", ""), format!("book.txt: {}\n", names.join(" ")));
+    }
+}
@@ -0,0 +1,224 @@
+// decoding of input files
+//
+// files are decoded a block at a time, so every decoder takes the bytes read so
+// far and leaves a character split by the end of the block for the next call
+
+/* ################################# Define Structs ################################# */
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Latin1,
+    // the byte order mark decides, big endian without one
+    Utf16,
+    Utf16Le,
+    Utf16Be,
+}
+
+/* ################################# Encoding Functions ################################# */
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Encoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "utf-16" | "utf16" => Some(Encoding::Utf16),
+            "utf-16le" | "utf16le" => Some(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" => Some(Encoding::Utf16Be),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Latin1 => "Latin-1",
+            Encoding::Utf16 | Encoding::Utf16Le | Encoding::Utf16Be => "UTF-16",
+        }
+    }
+
+    // looks at the start of a file for a byte order mark, returns the encoding to read
+    // the file with and the length of the mark, None if more bytes are needed to tell
+    pub fn detect(self, bytes: &[u8], eof: bool) -> Option<(Encoding, usize)> {
+        const UTF8_MARK: [u8; 3] = [0xEF, 0xBB, 0xBF];
+
+        if self == Encoding::Utf8 {
+            if bytes.len() < UTF8_MARK.len() && UTF8_MARK.starts_with(bytes) && !eof {
+                return None;
+            } else if bytes.starts_with(&UTF8_MARK) {
+                return Some((Encoding::Utf8, UTF8_MARK.len()));
+            }
+        }
+
+        // a UTF-16 mark is invalid UTF-8, so UTF-8 input may switch to UTF-16
+        if matches!(self, Encoding::Utf8 | Encoding::Utf16) {
+            if bytes.len() < 2 && !eof {
+                return None;
+            } else if bytes.starts_with(&[0xFE, 0xFF]) {
+                return Some((Encoding::Utf16Be, 2));
+            } else if bytes.starts_with(&[0xFF, 0xFE]) {
+                return Some((Encoding::Utf16Le, 2));
+            }
+        }
+
+        match self {
+            Encoding::Utf16 => Some((Encoding::Utf16Be, 0)),
+            _ => Some((self, 0)),
+        }
+    }
+
+    // appends the text of as many bytes as can be decoded, returns the number of bytes used
+    // or the offset of the first invalid byte, incomplete characters are only invalid at the end of the file
+    pub fn decode(self, bytes: &[u8], eof: bool, text: &mut String) -> Result<usize, usize> {
+        match self {
+            Encoding::Utf8 => decode_utf8(bytes, eof, text),
+            Encoding::Latin1 => {
+                text.extend(bytes.iter().map(|&byte| byte as char));
+                Ok(bytes.len())
+            }
+            Encoding::Utf16 | Encoding::Utf16Be => decode_utf16(bytes, eof, text, u16::from_be_bytes),
+            Encoding::Utf16Le => decode_utf16(bytes, eof, text, u16::from_le_bytes),
+        }
+    }
+}
+
+fn decode_utf8(bytes: &[u8], eof: bool, text: &mut String) -> Result<usize, usize> {
+    let valid: usize = match std::str::from_utf8(bytes) {
+        Ok(_) => bytes.len(),
+        Err(error) if error.error_len().is_none() && !eof => error.valid_up_to(),
+        Err(error) => return Err(error.valid_up_to()),
+    };
+
+    text.push_str(std::str::from_utf8(&bytes[..valid]).unwrap());
+    Ok(valid)
+}
+
+fn decode_utf16(bytes: &[u8], eof: bool, text: &mut String, unit: fn([u8; 2]) -> u16) -> Result<usize, usize> {
+    let mut pos: usize = 0;
+
+    while pos + 2 <= bytes.len() {
+        let first: u16 = unit([bytes[pos], bytes[pos + 1]]);
+
+        let (c, width): (Option<char>, usize) = if (0xD800..0xDC00).contains(&first) {
+            // a high surrogate needs the low surrogate that follows it
+            if pos + 4 > bytes.len() {
+                break;
+            }
+
+            let second: u16 = unit([bytes[pos + 2], bytes[pos + 3]]);
+            if !(0xDC00..0xE000).contains(&second) {
+                return Err(pos);
+            }
+
+            let code: u32 = 0x10000 + (((first as u32) - 0xD800) << 10) + ((second as u32) - 0xDC00);
+            (char::from_u32(code), 4)
+        } else {
+            (char::from_u32(first as u32), 2)
+        };
+
+        match c {
+            Some(c) => text.push(c),
+            None => return Err(pos),
+        }
+        pos += width;
+    }
+
+    if eof && pos < bytes.len() {
+        return Err(pos);
+    }
+
+    Ok(pos)
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // decodes bytes handed over in blocks of the given size, the way a file is read
+    fn decode_in_blocks(encoding: Encoding, bytes: &[u8], block: usize) -> Result<String, usize> {
+        let mut text: String = String::new();
+        let mut pending: Vec<u8> = Vec::new();
+        let mut decoded: usize = 0;
+
+        // errors are reported as offsets in the whole input
+        for (i, chunk) in bytes.chunks(block).enumerate() {
+            pending.extend_from_slice(chunk);
+            let eof: bool = (i + 1) * block >= bytes.len();
+            let used: usize = encoding.decode(&pending, eof, &mut text).map_err(|offset| decoded + offset)?;
+            pending.drain(..used);
+            decoded += used;
+        }
+
+        Ok(text)
+    }
+
+    fn utf16(text: &str, unit: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        text.encode_utf16().flat_map(unit).collect()
+    }
+
+    #[test]
+    fn utf16_split_across_blocks() {
+        let text: &str = "a😀é\u{FFFD}z";
+        let big: Vec<u8> = utf16(text, u16::to_be_bytes);
+        let little: Vec<u8> = utf16(text, u16::to_le_bytes);
+
+        for block in 1..=big.len() {
+            assert_eq!(decode_in_blocks(Encoding::Utf16Be, &big, block), Ok(text.to_string()), "block {}", block);
+            assert_eq!(decode_in_blocks(Encoding::Utf16Le, &little, block), Ok(text.to_string()), "block {}", block);
+        }
+    }
+
+    #[test]
+    fn utf16_errors() {
+        let mut text: String = String::new();
+
+        // an odd trailing byte and a high surrogate without its pair wait for more input, but not at the end
+        assert_eq!(decode_utf16(&[0x00, 0x61, 0x00], false, &mut text, u16::from_be_bytes), Ok(2));
+        assert_eq!(decode_utf16(&[0x00, 0x61, 0x00], true, &mut text, u16::from_be_bytes), Err(2));
+        assert_eq!(decode_utf16(&[0xD8, 0x3D], false, &mut text, u16::from_be_bytes), Ok(0));
+        assert_eq!(decode_utf16(&[0xD8, 0x3D], true, &mut text, u16::from_be_bytes), Err(0));
+
+        // a high surrogate followed by anything else, and a lone low surrogate
+        assert_eq!(decode_utf16(&[0x00, 0x61, 0xD8, 0x3D, 0x00, 0x61], false, &mut text, u16::from_be_bytes), Err(2));
+        assert_eq!(decode_utf16(&[0xDC, 0x00], false, &mut text, u16::from_be_bytes), Err(0));
+    }
+
+    #[test]
+    fn utf8_split_across_blocks() {
+        let text: &str = "a😀é€z";
+        for block in 1..=text.len() {
+            assert_eq!(decode_in_blocks(Encoding::Utf8, text.as_bytes(), block), Ok(text.to_string()), "block {}", block);
+        }
+        assert_eq!(decode_in_blocks(Encoding::Utf8, &[0x61, 0xF0, 0x9F], 2), Err(1));
+    }
+
+    #[test]
+    fn detect_marks() {
+        assert_eq!(Encoding::Utf8.detect(&[0xEF, 0xBB, 0xBF, 0x61], false), Some((Encoding::Utf8, 3)));
+        assert_eq!(Encoding::Utf8.detect(&[0xFF, 0xFE, 0x61, 0x00], false), Some((Encoding::Utf16Le, 2)));
+        assert_eq!(Encoding::Utf8.detect(&[0xFE, 0xFF], false), Some((Encoding::Utf16Be, 2)));
+        assert_eq!(Encoding::Utf8.detect(b"ab", false), Some((Encoding::Utf8, 0)));
+        assert_eq!(Encoding::Utf16.detect(&[0xFF, 0xFE], false), Some((Encoding::Utf16Le, 2)));
+        assert_eq!(Encoding::Utf16.detect(&[0x00, 0x61], false), Some((Encoding::Utf16Be, 0)));
+        assert_eq!(Encoding::Latin1.detect(&[0xFF, 0xFE], false), Some((Encoding::Latin1, 0)));
+        assert_eq!(Encoding::Utf16Be.detect(&[0xFF, 0xFE], false), Some((Encoding::Utf16Be, 0)));
+    }
+
+    #[test]
+    fn detect_marks_split_across_blocks() {
+        // the start of a mark needs more bytes before anything is decided
+        assert_eq!(Encoding::Utf8.detect(&[], false), None);
+        assert_eq!(Encoding::Utf8.detect(&[0xEF], false), None);
+        assert_eq!(Encoding::Utf8.detect(&[0xEF, 0xBB], false), None);
+        assert_eq!(Encoding::Utf8.detect(&[0xFF], false), None);
+        assert_eq!(Encoding::Utf16.detect(&[0xFE], false), None);
+
+        // unless the file ends there
+        assert_eq!(Encoding::Utf8.detect(&[0xEF, 0xBB], true), Some((Encoding::Utf8, 0)));
+        assert_eq!(Encoding::Utf8.detect(&[], true), Some((Encoding::Utf8, 0)));
+        assert_eq!(Encoding::Utf16.detect(&[0xFE], true), Some((Encoding::Utf16Be, 0)));
+    }
+}
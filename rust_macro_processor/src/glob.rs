@@ -0,0 +1,235 @@
+// glob pattern expansion for the \includeall macro
+//
+// supported wildcards (inside a single path component):
+//   *      any run of characters
+//   ?      any single character
+//   [abc]  one of the listed characters, ranges like [a-z], negation with [!abc]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/* ################################# Glob Functions ################################# */
+
+// returns every regular file matching the pattern, sorted by path
+// relative patterns are resolved against base, an empty base meaning the current directory,
+// when confined only directories that resolve to somewhere below base are listed
+pub fn expand(base: &Path, pattern: &str, confined: bool) -> Vec<PathBuf> {
+    let mut matches: Vec<PathBuf> = Vec::new();
+
+    let (base, rest) = if let Some(stripped) = pattern.strip_prefix('/') {
+        (PathBuf::from("/"), stripped)
+    } else {
+        (base.to_path_buf(), pattern)
+    };
+
+    let components: Vec<&str> = rest.split('/').filter(|part| !part.is_empty()).collect();
+    if components.is_empty() {
+        return matches;
+    }
+
+    let root: Option<&Path> = if confined { Some(&base) } else { None };
+    walk(&base, &components, root, &mut matches);
+
+    matches.sort();
+    matches
+}
+
+fn walk(dir: &Path, components: &[&str], root: Option<&Path>, matches: &mut Vec<PathBuf>) {
+    let component: &str = components[0];
+    let is_last: bool = components.len() == 1;
+
+    // a symlink may lead out of the root, so the real location of every directory is checked
+    if let Some(root) = root {
+        if !fs::canonicalize(dir).is_ok_and(|resolved| resolved.starts_with(root)) {
+            return;
+        }
+    }
+
+    if !has_wildcard(component) {
+        let path: PathBuf = dir.join(component);
+        visit(path, is_last, &components[1..], root, matches);
+        return;
+    }
+
+    let read_dir_path: &Path = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let entries = match fs::read_dir(read_dir_path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name: &str = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        // like the shell, hidden files are only matched by an explicit leading dot
+        if name.starts_with('.') && !component.starts_with('.') {
+            continue;
+        }
+
+        if matches_component(name, component) {
+            visit(dir.join(name), is_last, &components[1..], root, matches);
+        }
+    }
+}
+
+fn visit(path: PathBuf, is_last: bool, rest: &[&str], root: Option<&Path>, matches: &mut Vec<PathBuf>) {
+    if is_last {
+        if path.is_file() {
+            matches.push(path);
+        }
+    } else if path.is_dir() {
+        walk(&path, rest, root, matches);
+    }
+}
+
+fn has_wildcard(component: &str) -> bool {
+    component.contains(['*', '?', '['])
+}
+
+// matches a single file name against a single pattern component
+fn matches_component(name: &str, pattern: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+
+    let mut n: usize = 0;
+    let mut p: usize = 0;
+
+    // position to resume from after the most recent '*'
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+            continue;
+        }
+
+        if p < pattern.len() {
+            if let Some(next_p) = match_single(&pattern, p, name[n]) {
+                p = next_p;
+                n += 1;
+                continue;
+            }
+        }
+
+        match star {
+            Some((star_p, star_n)) => {
+                p = star_p + 1;
+                n = star_n + 1;
+                star = Some((star_p, star_n + 1));
+            }
+            None => return false,
+        }
+    }
+
+    while p < pattern.len() && pattern[p] == '*' {
+        p += 1;
+    }
+
+    p == pattern.len()
+}
+
+// matches one name character at pattern[p], returning the index after the consumed pattern token
+fn match_single(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match pattern[p] {
+        '?' => Some(p + 1),
+        '[' => match_class(pattern, p, c),
+        literal => {
+            if literal == c {
+                Some(p + 1)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn match_class(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    let mut i: usize = p + 1;
+    let negated: bool = i < pattern.len() && pattern[i] == '!';
+    if negated {
+        i += 1;
+    }
+
+    let mut found: bool = false;
+    let mut first: bool = true;
+
+    // a ']' directly after the opening bracket is a literal member
+    while i < pattern.len() && (first || pattern[i] != ']') {
+        first = false;
+
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            if pattern[i] <= c && c <= pattern[i + 2] {
+                found = true;
+            }
+            i += 3;
+        } else {
+            if pattern[i] == c {
+                found = true;
+            }
+            i += 1;
+        }
+    }
+
+    if i >= pattern.len() {
+        // unterminated class, treat the bracket as a literal character
+        return if c == '[' { Some(p + 1) } else { None };
+    }
+
+    if found != negated {
+        Some(i + 1)
+    } else {
+        None
+    }
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stars_and_question_marks() {
+        assert!(matches_component("chapter1.tex", "*.tex"));
+        assert!(matches_component(".tex", "*.tex"));
+        assert!(matches_component("a.b.tex", "*.tex"));
+        assert!(!matches_component("chapter1.txt", "*.tex"));
+        assert!(matches_component("ab", "a*b*"));
+        assert!(matches_component("axxbxxb", "a*b"));
+        assert!(!matches_component("axxbxxc", "a*b"));
+        assert!(matches_component("a1", "a?"));
+        assert!(!matches_component("a", "a?"));
+        assert!(!matches_component("a12", "a?"));
+        assert!(matches_component("anything", "**"));
+        assert!(matches_component("", "*"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches_component("ch3", "ch[0-9]"));
+        assert!(!matches_component("chx", "ch[0-9]"));
+        assert!(matches_component("chb", "ch[abc]"));
+        assert!(!matches_component("chb", "ch[!abc]"));
+        assert!(matches_component("chd", "ch[!abc]"));
+        assert!(matches_component("a-", "a[x-]"));
+        assert!(matches_component("a]", "a[]x]"));
+        assert!(matches_component("ax", "a[]x]"));
+    }
+
+    #[test]
+    fn class_tokens() {
+        let pattern: Vec<char> = "[a-c]x".chars().collect();
+        assert_eq!(match_class(&pattern, 0, 'b'), Some(5));
+        assert_eq!(match_class(&pattern, 0, 'd'), None);
+
+        // an unterminated class is a literal bracket
+        let pattern: Vec<char> = "[ab".chars().collect();
+        assert_eq!(match_class(&pattern, 0, '['), Some(1));
+        assert_eq!(match_class(&pattern, 0, 'a'), None);
+        assert!(matches_component("[ab", "[ab"));
+    }
+}
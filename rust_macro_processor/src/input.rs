@@ -0,0 +1,286 @@
+// pending input of the state machine
+//
+// the input is a stack of shared, immutable chunks that are read front to back,
+// so pushing a macro body only stores a reference to it instead of copying it,
+// input files sit on the same stack as sources that are read as they are reached
+
+use std::rc::Rc;
+
+use crate::catcode::Catcodes;
+use crate::scan::find_any;
+use crate::source::Source;
+
+/* ################################# Define Structs ################################# */
+
+enum Chunk {
+    Text {
+        text: Rc<str>,
+        start: usize,
+        end: usize,
+    },
+    File(Box<Source>),
+}
+
+#[derive(Default)]
+pub struct Input {
+    // the chunk read next is the last one
+    chunks: Vec<Chunk>,
+    // bytes of text chunks, the unread part of files is not counted
+    len: usize,
+    // the texts read while a trail is kept, see start_trail
+    trail: Option<Trail>,
+}
+
+// byte ranges of shared texts, in the order they were read
+pub type Pieces = Vec<(Rc<str>, usize, usize)>;
+
+#[derive(Default)]
+struct Trail {
+    pieces: Pieces,
+    // some of the text came from a file, it cannot be shared
+    from_file: bool,
+}
+
+/* ################################# Input Functions ################################# */
+
+impl Input {
+    pub fn from_string(text: String) -> Input {
+        let mut input: Input = Input::default();
+        input.push_string(text);
+        input
+    }
+
+    pub fn is_empty(&mut self, catcodes: &Catcodes) -> bool {
+        self.peek(catcodes).is_none()
+    }
+
+    // number of bytes of text still to be read
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // number of chunks on the stack, a call's result has been read once the stack is back below it
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    // the file name and line of the innermost file being read, None if no file is left
+    pub fn position(&self) -> Option<(&str, usize)> {
+        self.chunks.iter().rev().find_map(|chunk| match chunk {
+            Chunk::File(source) => Some(source.position()),
+            Chunk::Text { .. } => None,
+        })
+    }
+
+    // the file name and line if the next character is read from a file rather than from a pushed text
+    pub fn reading_position(&self) -> Option<(&str, usize)> {
+        match self.chunks.last()? {
+            Chunk::File(source) => Some(source.position()),
+            Chunk::Text { .. } => None,
+        }
+    }
+
+    // the text the next character is read from and its offset in it, None while reading a file
+    pub fn text_origin(&self) -> Option<(&Rc<str>, usize)> {
+        match self.chunks.last()? {
+            Chunk::Text { text, start, .. } => Some((text, *start)),
+            Chunk::File(_) => None,
+        }
+    }
+
+    // the start of the pending input for the debugger, cut after limit bytes, of a file only
+    // the part already read is shown, with its comments not yet stripped
+    pub fn preview(&self, limit: usize) -> String {
+        let mut preview: String = String::new();
+
+        for chunk in self.chunks.iter().rev() {
+            if preview.len() > limit {
+                break;
+            }
+
+            match chunk {
+                Chunk::Text { text, start, end } => preview.push_str(&text[*start..*end]),
+                Chunk::File(source) => {
+                    let (pending, block): (Option<char>, &str) = source.unread();
+                    preview.extend(pending);
+                    preview.push_str(block);
+                    break;
+                }
+            }
+        }
+
+        if preview.len() > limit {
+            let mut cut: usize = limit;
+            while !preview.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            preview.truncate(cut);
+            preview.push_str("...");
+        }
+
+        preview
+    }
+
+    // removes and returns the next character
+    pub fn pop(&mut self, catcodes: &Catcodes) -> Option<char> {
+        loop {
+            match self.chunks.last_mut()? {
+                Chunk::Text { text, start, end } => {
+                    let c: char = text[*start..*end].chars().next()?;
+
+                    if let Some(trail) = &mut self.trail {
+                        trail.add(text, *start, *start + c.len_utf8());
+                    }
+                    *start += c.len_utf8();
+                    self.len -= c.len_utf8();
+
+                    if start == end {
+                        self.chunks.pop();
+                    }
+
+                    return Some(c);
+                }
+
+                Chunk::File(source) => match source.pop(catcodes) {
+                    Some(c) => {
+                        if let Some(trail) = &mut self.trail {
+                            trail.from_file = true;
+                        }
+                        return Some(c);
+                    }
+                    None => {
+                        self.chunks.pop();
+                    }
+                },
+            }
+        }
+    }
+
+    // returns the next character without removing it
+    pub fn peek(&mut self, catcodes: &Catcodes) -> Option<char> {
+        loop {
+            match self.chunks.last_mut()? {
+                Chunk::Text { text, start, end } => return text[*start..*end].chars().next(),
+
+                Chunk::File(source) => match source.peek(catcodes) {
+                    Some(c) => return Some(c),
+                    None => {
+                        self.chunks.pop();
+                    }
+                },
+            }
+        }
+    }
+
+    // moves the text up to the next escape or active character (or the end of the current chunk) to the output,
+    // returns the number of bytes moved
+    pub fn pop_text_run(&mut self, output: &mut String, catcodes: &Catcodes) -> usize {
+        self.pop_run(output, catcodes.text_stops(), catcodes.source_stops())
+    }
+
+    // moves the text up to the next escape or group character (or the end of the current chunk) to a macro
+    // argument, returns the number of bytes moved
+    pub fn pop_argument_run(&mut self, argument: &mut String, catcodes: &Catcodes) -> usize {
+        self.pop_run(argument, catcodes.argument_stops(), catcodes.argument_source_stops())
+    }
+
+    fn pop_run(&mut self, output: &mut String, text_stops: &[u8], source_stops: &[u8]) -> usize {
+        match self.chunks.last_mut() {
+            Some(Chunk::Text { text, start, end }) => {
+                let run_text: &str = &text[*start..*end];
+                let run: usize = find_any(run_text.as_bytes(), text_stops).unwrap_or(run_text.len());
+
+                output.push_str(&run_text[..run]);
+                if let Some(trail) = &mut self.trail {
+                    trail.add(text, *start, *start + run);
+                }
+                *start += run;
+                self.len -= run;
+
+                if start == end {
+                    self.chunks.pop();
+                }
+
+                run
+            }
+
+            Some(Chunk::File(source)) => {
+                let run: usize = source.pop_run(output, source_stops);
+                if let Some(trail) = &mut self.trail {
+                    trail.from_file |= run > 0;
+                }
+                run
+            }
+
+            None => 0,
+        }
+    }
+
+    // starts keeping track of the texts the input is read from, replacing the trail kept so far,
+    // --coverage puts the branches of conditionals back as the texts of the macro bodies they were read from
+    pub fn start_trail(&mut self) {
+        self.trail = Some(Trail::default());
+    }
+
+    // stops the trail and returns the texts read since it was started, None if some of it came from a file
+    pub fn take_trail(&mut self) -> Option<Pieces> {
+        self.trail.take().filter(|trail| !trail.from_file).map(|trail| trail.pieces)
+    }
+
+    // puts texts in front of the pending input, in the order they are read
+    pub fn push_pieces(&mut self, pieces: &[(Rc<str>, usize, usize)]) {
+        for (text, start, end) in pieces.iter().rev() {
+            self.push_shared(text, *start, *end);
+        }
+    }
+
+    // puts a file in front of the pending input
+    pub fn push_source(&mut self, source: Source) {
+        self.chunks.push(Chunk::File(Box::new(source)));
+    }
+
+    // puts text in front of the pending input
+    pub fn push_string(&mut self, text: String) {
+        if !text.is_empty() {
+            let text: Rc<str> = Rc::from(text);
+            self.push_shared(&text, 0, text.len());
+        }
+    }
+
+    // puts a byte range of shared text in front of the pending input without copying it
+    pub fn push_shared(&mut self, text: &Rc<str>, start: usize, end: usize) {
+        if start < end {
+            self.chunks.push(Chunk::Text {
+                text: Rc::clone(text),
+                start,
+                end,
+            });
+            self.len += end - start;
+        }
+    }
+
+    // puts a macro body in front of the pending input with every parameter replaced by the argument,
+    // params holds the byte offsets of the unescaped parameter characters in the body
+    pub fn push_expansion(&mut self, body: &Rc<str>, params: &[usize], argument: &Rc<str>) {
+        let mut end: usize = body.len();
+
+        // the chunk read last goes first
+        for &param in params.iter().rev() {
+            self.push_shared(body, param + 1, end);
+            self.push_shared(argument, 0, argument.len());
+            end = param;
+        }
+
+        self.push_shared(body, 0, end);
+    }
+}
+
+impl Trail {
+    // a range following the previous one in the same text extends it
+    fn add(&mut self, text: &Rc<str>, start: usize, end: usize) {
+        match self.pieces.last_mut() {
+            Some((last_text, _, last_end)) if Rc::ptr_eq(last_text, text) && *last_end == start => *last_end = end,
+            _ => self.pieces.push((Rc::clone(text), start, end)),
+        }
+    }
+}
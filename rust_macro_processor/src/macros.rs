@@ -0,0 +1,167 @@
+// table of user defined macros
+//
+// macro names are interned: the first definition of a name assigns it a compact
+// symbol, and the definitions are stored in a vector indexed by that symbol, so a
+// macro call costs one hash lookup and listing the macros follows definition order
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::catcode::{Catcode, Catcodes};
+
+/* ################################# Define Structs ################################# */
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+// a user defined macro, the body is shared with the input whenever the macro is expanded
+pub struct MacroDef {
+    pub body: Rc<str>,
+    // byte offsets of the unescaped parameter characters in the body
+    pub params: Vec<usize>,
+    // declared with \pure, calls may be answered from the memo cache
+    pub pure: bool,
+}
+
+#[derive(Default)]
+pub struct MacroTable {
+    symbols: HashMap<Rc<str>, Symbol>,
+    names: Vec<Rc<str>>,
+    // indexed by symbol, None while the name is undefined
+    definitions: Vec<Option<MacroDef>>,
+    // indexed by symbol, counts the definitions and undefinitions of the name
+    generations: Vec<u64>,
+}
+
+/* ################################# Macro Definition Functions ################################# */
+
+impl MacroDef {
+    // the parameters are found with the catcodes in effect at the definition
+    pub fn new(body: &str, catcodes: &Catcodes) -> MacroDef {
+        let mut params: Vec<usize> = Vec::new();
+        let mut escape_flag: bool = false;
+
+        // an escaped parameter character is kept as is and never substituted
+        for (i, c) in body.char_indices() {
+            if escape_flag {
+                escape_flag = false;
+            } else if catcodes.get(c) == Catcode::Escape {
+                escape_flag = true;
+            } else if catcodes.get(c) == Catcode::Parameter {
+                params.push(i);
+            }
+        }
+
+        MacroDef {
+            body: Rc::from(body),
+            params,
+            pure: false,
+        }
+    }
+
+    // the replacement text of a call, the expansion itself shares the body instead
+    pub fn substitute(&self, argument: &str) -> String {
+        let mut text: String = String::with_capacity(self.body.len());
+        let mut start: usize = 0;
+
+        for &param in &self.params {
+            text.push_str(&self.body[start..param]);
+            text.push_str(argument);
+            start = param + 1;
+        }
+
+        text.push_str(&self.body[start..]);
+        text
+    }
+}
+
+/* ################################# Macro Table Functions ################################# */
+
+impl MacroTable {
+    // returns the symbol of a currently defined macro
+    pub fn lookup(&self, name: &str) -> Option<Symbol> {
+        let symbol: Symbol = *self.symbols.get(name)?;
+        self.definitions[symbol.0 as usize].as_ref().map(|_| symbol)
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.lookup(name).is_some()
+    }
+
+    // the symbol has to come from a lookup of a macro that is still defined
+    pub fn definition(&self, symbol: Symbol) -> &MacroDef {
+        self.definitions[symbol.0 as usize].as_ref().unwrap()
+    }
+
+    // returns false if the macro is already defined
+    pub fn define(&mut self, name: &str, definition: MacroDef) -> bool {
+        let symbol: Symbol = self.intern(name);
+        let slot: &mut Option<MacroDef> = &mut self.definitions[symbol.0 as usize];
+
+        if slot.is_some() {
+            return false;
+        }
+
+        *slot = Some(definition);
+        self.generations[symbol.0 as usize] += 1;
+        true
+    }
+
+    // returns false if the macro is not defined
+    pub fn undefine(&mut self, name: &str) -> bool {
+        let symbol: Symbol = match self.lookup(name) {
+            Some(symbol) => symbol,
+            None => return false,
+        };
+
+        self.definitions[symbol.0 as usize] = None;
+        self.generations[symbol.0 as usize] += 1;
+        true
+    }
+
+    // returns false if the macro is not defined
+    pub fn set_pure(&mut self, name: &str) -> bool {
+        match self.lookup(name) {
+            Some(symbol) => {
+                self.definitions[symbol.0 as usize].as_mut().unwrap().pure = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // changes whenever the name is defined or undefined, 0 for names never defined
+    pub fn generation(&self, name: &str) -> u64 {
+        match self.symbols.get(name) {
+            Some(symbol) => self.generations[symbol.0 as usize],
+            None => 0,
+        }
+    }
+
+    pub fn symbol_generation(&self, symbol: Symbol) -> u64 {
+        self.generations[symbol.0 as usize]
+    }
+
+    // the defined macros in the order their names were first defined
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &MacroDef)> {
+        self.names.iter().zip(self.definitions.iter()).filter_map(|(name, definition)| {
+            definition.as_ref().map(|definition| (name.as_ref(), definition))
+        })
+    }
+
+    fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(symbol) = self.symbols.get(name) {
+            return *symbol;
+        }
+
+        let symbol: Symbol = Symbol(self.names.len() as u32);
+        let name: Rc<str> = Rc::from(name);
+
+        self.symbols.insert(Rc::clone(&name), symbol);
+        self.names.push(name);
+        self.definitions.push(None);
+        self.generations.push(0);
+
+        symbol
+    }
+}
@@ -2,12 +2,18 @@
 #![deny(unsafe_code)]
 
 // use statements
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
-use std::io::BufRead;
-use std::panic;
+use std::io::Write;
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 // a similar 'die' macro with the C version
 macro_rules! die {
@@ -17,6 +23,44 @@ macro_rules! die {
     };
 }
 
+// logs an expansion step to stderr while tracing is on
+macro_rules! trace {
+    ($session:expr, $input:expr, $suspended:expr, $($arg:tt)*) => {
+        if $session.tracing {
+            eprintln!("trace: {} depth {}: {}", source_position($input, $suspended), $session.trace_depth(), format_args!($($arg)*));
+        }
+    };
+}
+
+// modules are declared after 'die' so that they can use it
+mod catcode;
+mod coverage;
+mod debug;
+mod defs;
+mod depend;
+mod encoding;
+mod glob;
+mod input;
+mod macros;
+mod memo;
+mod output;
+mod profile;
+mod repl;
+mod scan;
+mod source;
+
+use catcode::{Catcode, Catcodes};
+use coverage::Coverage;
+use debug::Debugger;
+use depend::Dependencies;
+use encoding::Encoding;
+use input::{Input, Pieces};
+use macros::{MacroDef, MacroTable, Symbol};
+use memo::{Memo, MemoKey};
+use output::{Newline, NewlineWriter, Spool, TempFile};
+use profile::Profiler;
+use source::Source;
+
 /* ################################# Define Structs ################################# */
 
 struct MacroArgs {
@@ -25,15 +69,86 @@ struct MacroArgs {
     arg_3: String,
 }
 
-/* ################################# State Machines ################################# */
+// a suspended evaluation waiting for a nested expansion to finish
+struct Frame {
+    input: Input,
+    output: String,
+    resume: Resume,
+}
+
+// what to do with the result of a nested expansion once its input runs out
+enum Resume {
+    // prepend the unexpanded BEFORE argument to the expanded AFTER argument
+    ExpandAfter(String),
+    // drop the library output and retry the call of the named macro
+    Autoload(String),
+    // cache the result of a pure macro call and output it
+    Memo(MemoKey),
+}
+
+#[derive(Default)]
+struct Settings {
+    lenient_include_all: bool,
+    autoload_dirs: Vec<PathBuf>,
+    sandbox_root: Option<PathBuf>,
+    max_include_size: Option<u64>,
+    max_steps: Option<u64>,
+    max_depth: Option<usize>,
+    max_output: Option<usize>,
+    max_input: Option<usize>,
+    spool: Spool,
+    input_encoding: Encoding,
+    newline: Newline,
+    catcodes: Catcodes,
+    dump_macros: bool,
+    output: Option<PathBuf>,
+    include_dirs: Vec<PathBuf>,
+    // -D, -U and --defs definitions in command line order, None undefines the name
+    defines: Vec<(String, Option<String>)>,
+    dependencies: Dependencies,
+    dependency_file: Option<PathBuf>,
+    dependency_target: Option<String>,
+    watch: bool,
+    repl: bool,
+    trace: bool,
+    debug: bool,
+    profile: bool,
+    profile_stacks: Option<PathBuf>,
+    coverage: Option<PathBuf>,
+    coverage_format: coverage::Format,
+}
 
-enum CommentState {
-    PlainText,
-    Escape,
-    StartComment,
-    EndComment,
+#[derive(Default)]
+struct Session {
+    loaded_libraries: HashSet<PathBuf>,
+    library_index: Option<HashMap<String, PathBuf>>,
+    // bookkeeping for the resource limits
+    limited: bool,
+    // the name, nesting level and result base of the calls whose result is still being read, kept
+    // while a limit is set so that limit errors can name the innermost one, see enter_call
+    active_calls: Vec<(String, usize, Option<usize>)>,
+    steps: u64,
+    depth: usize,
+    suspended_input: usize,
+    suspended_output: usize,
+    written_output: usize,
+    memo: Memo,
+    // every file read, in the order they were first opened
+    files_read: Vec<PathBuf>,
+    // the modification time of each file read when it was opened, for --watch
+    files_modified: Vec<Option<SystemTime>>,
+    files_seen: HashSet<PathBuf>,
+    // --trace, switched by \traceon and \traceoff
+    tracing: bool,
+    // the nesting level and result base of the calls traced whose result is still being read, see enter_call
+    traced_calls: Vec<(usize, Option<usize>)>,
+    debugger: Option<Debugger>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
 }
 
+/* ################################# State Machines ################################# */
+
 enum ParseState {
     Text,
     Backslash,
@@ -48,6 +163,12 @@ enum MacroState {
     IfDef,
     ExpandAfter,
     Include,
+    IncludeAll,
+    Pure,
+    Catcode,
+    Active,
+    TraceOn,
+    TraceOff,
     Custom,
 }
 
@@ -59,102 +180,671 @@ enum ArgState {
 
 /* ################################# Strip Comments Function ################################# */
 
-fn strip_comments(input: &mut String, input_file: Option<&str>) {
-    let mut file: Box<dyn BufRead> = match input_file {
+// puts a file (or stdin) in front of the input, its comments are stripped as it is read
+fn strip_comments(input: &mut Input, input_file: Option<&str>, encoding: Encoding) {
+    match input_file {
         Some(filename) => {
-            let file = File::open(filename);
-            if file.is_err() {
-                die!("Unable to open file!");
+            match File::open(filename) {
+                Ok(file) => input.push_source(Source::new(Box::new(file), filename, None, encoding)),
+                Err(_) => {
+                    die!("Unable to open file!");
+                }
             }
-            else {
-                Box::new(io::BufReader::new(file.unwrap()))
+        }
+        None => input.push_source(Source::new(Box::new(io::stdin()), "<stdin>", None, encoding)),
+    }
+}
+
+/* ################################# Include Policy Functions ################################# */
+
+// per-file size cap used in sandbox mode when no explicit limit is given
+const DEFAULT_SANDBOX_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+// maps the PATH argument of \include to the file that should be read
+fn resolve_include(path: &str, settings: &Settings) -> PathBuf {
+    let direct: PathBuf = match &settings.sandbox_root {
+        Some(root) => {
+            check_sandbox_path(path);
+            root.join(path)
+        }
+        None => PathBuf::from(path),
+    };
+
+    // relative paths that do not exist are looked up in the include directories
+    if direct.exists() || Path::new(path).has_root() {
+        return direct;
+    }
+
+    for dir in &settings.include_dirs {
+        let candidate: PathBuf = dir.join(path);
+        if candidate.is_file() {
+            return candidate;
+        }
+    }
+
+    direct
+}
+
+// expands the PATTERN argument of \includeall into the files that should be read
+fn resolve_include_all(pattern: &str, settings: &Settings) -> Vec<PathBuf> {
+    let files: Vec<PathBuf> = match &settings.sandbox_root {
+        Some(root) => {
+            check_sandbox_path(pattern);
+            glob::expand(root, pattern, true)
+        }
+        None => glob::expand(Path::new(""), pattern, false),
+    };
+
+    // the first include directory with a match is used, like for \include
+    if !files.is_empty() || Path::new(pattern).has_root() {
+        return files;
+    }
+
+    for dir in &settings.include_dirs {
+        let files: Vec<PathBuf> = glob::expand(dir, pattern, false);
+        if !files.is_empty() {
+            return files;
+        }
+    }
+
+    files
+}
+
+// refuses paths that could leave the sandbox before anything is looked up on disk
+fn check_sandbox_path(path: &str) {
+    if Path::new(path).has_root() {
+        die!("Absolute include path {} is not allowed in the sandbox", path);
+    }
+
+    if Path::new(path).components().any(|component| component == Component::ParentDir) {
+        die!("Include path {} is not allowed in the sandbox", path);
+    }
+}
+
+// reads an included file and pushes it onto the input, enforcing the sandbox and size limits
+fn include_file(input: &mut Input, path: &Path, settings: &Settings, session: &mut Session) {
+    let file: File = match &settings.sandbox_root {
+        Some(root) => open_sandboxed(path, root),
+        None => match File::open(path) {
+            Ok(file) => file,
+            Err(_) => {
+                die!("Unable to open file!");
             }
+        },
+    };
+
+    // the size is checked up front and again while reading, in case the file grows
+    if let Some(limit) = settings.max_include_size {
+        if file.metadata().is_ok_and(|metadata| metadata.len() > limit) {
+            die!("Included file {} exceeds the size limit of {} bytes", path.display(), limit);
+        }
+    }
+
+    input.push_source(Source::new(Box::new(file), &path.to_string_lossy(), settings.max_include_size, settings.input_encoding));
+    session.record_file(path);
+}
+
+// opens a file only if it is a regular file that really lives below the sandbox root
+fn open_sandboxed(path: &Path, root: &Path) -> File {
+    // resolving the path follows every symlink before comparing against the root, a path
+    // outside of it gets the same error as a missing one so that nothing is learned about it
+    let resolved: PathBuf = match fs::canonicalize(path) {
+        Ok(resolved) if resolved.starts_with(root) => resolved,
+        _ => {
+            die!("Unable to open file!");
         }
-        None => Box::new(io::BufReader::new(io::stdin())),
     };
 
-    let mut state = CommentState::PlainText;
-    let mut buffer = String::new();
-    let mut str = String::new();
+    // checked before opening since opening a fifo or device can block
+    if !fs::metadata(&resolved).is_ok_and(|metadata| metadata.is_file()) {
+        die!("Refusing to include non-regular file {}", resolved.strip_prefix(root).unwrap().display());
+    }
 
-    while let Ok(bytes) = file.read_to_string(&mut buffer) {
-        if bytes == 0 {
-            break;
+    let file: File = match File::open(&resolved) {
+        Ok(file) => file,
+        Err(_) => {
+            die!("Unable to open file!");
         }
+    };
 
-        for c in buffer.chars() {
-            match state {
-                CommentState::PlainText => {
-                    if c == '\\' {
-                        state = CommentState::Escape;
-                        str.push('\\');
-                    } else if c == '%' {
-                        state = CommentState::StartComment;
-                    } else {
-                        str.push(c);
-                    }
-                }
-                CommentState::Escape => {
-                    if c == '%' {
-                        str.pop();
-                    }
-                    str.push(c);
-                    state = CommentState::PlainText;
-                }
-                CommentState::StartComment => {
-                    if c == '\n' {
-                        state = CommentState::EndComment;
-                    }
+    // checked again on the open handle in case the path was swapped in between
+    if !file.metadata().is_ok_and(|metadata| metadata.is_file()) {
+        die!("Refusing to include non-regular file {}", resolved.strip_prefix(root).unwrap().display());
+    }
+
+    file
+}
+
+/* ################################# Resource Limit Functions ################################# */
+
+fn has_limits(settings: &Settings) -> bool {
+    settings.max_steps.is_some() || settings.max_depth.is_some() || settings.max_output.is_some() || settings.max_input.is_some()
+}
+
+impl Session {
+    // describes the innermost call whose result is being read for limit errors
+    fn context(&self) -> String {
+        match self.active_calls.last() {
+            Some((call, _, _)) => format!("while expanding {}", call),
+            None => String::from("outside any macro"),
+        }
+    }
+
+    // counts one macro invocation against the step limit, the escape character is None for active characters
+    fn step(&mut self, macro_name: &str, escape_char: Option<char>, settings: &Settings) {
+        self.steps += 1;
+
+        if let Some(limit) = settings.max_steps {
+            if self.steps > limit {
+                let escape: String = escape_char.map(String::from).unwrap_or_default();
+                die!("Expansion step limit of {} exceeded at {}{}", limit, escape, macro_name);
+            }
+        }
+    }
+
+    // sets the caller's input and output aside before a nested expansion
+    fn enter_nested(&mut self, input: &Input, output: &str, settings: &Settings) {
+        self.depth += 1;
+        self.suspended_input += input.len();
+        self.suspended_output += output.len();
+
+        if let Some(limit) = settings.max_depth {
+            if self.depth > limit {
+                die!("Expansion depth limit of {} exceeded {}", limit, self.context());
+            }
+        }
+    }
+
+    // starts the limits over for the next expansion, e.g. the next line of the REPL
+    fn reset_limits(&mut self) {
+        self.active_calls.clear();
+        self.steps = 0;
+        self.depth = 0;
+        self.suspended_input = 0;
+        self.suspended_output = 0;
+        self.written_output = 0;
+        self.memo.abort();
+    }
+
+    fn leave_nested(&mut self, input: &Input, output: &str) {
+        self.depth -= 1;
+        self.suspended_input -= input.len();
+        self.suspended_output -= output.len();
+    }
+
+    // the limits cover everything held in memory, including suspended callers
+    fn check_sizes(&self, input: &Input, output: &str, settings: &Settings) {
+        if let Some(limit) = settings.max_input {
+            if input.len() + self.suspended_input > limit {
+                die!("Pending input limit of {} bytes exceeded {}", limit, self.context());
+            }
+        }
+
+        if let Some(limit) = settings.max_output {
+            if output.len() + self.suspended_output + self.written_output > limit {
+                die!("Output limit of {} bytes exceeded {}", limit, self.context());
+            }
+        }
+    }
+}
+
+/* ################################# Autoload Functions ################################# */
+
+// name of the optional file in a library directory that maps macro names to files
+const LIBRARY_INDEX: &str = "autoload.idx";
+
+// reads the library file for an undefined macro, returns None if there is nothing (new) to load
+fn load_library(name: &str, settings: &Settings, session: &mut Session) -> Option<Input> {
+    let path: PathBuf = find_library_file(name, settings, session)?;
+
+    // every library file is loaded at most once
+    if !session.loaded_libraries.insert(path.clone()) {
+        return None;
+    }
+
+    // libraries are read like included files, so the sandbox and the size limit apply to them too
+    let mut library_input: Input = Input::default();
+    include_file(&mut library_input, &path, settings, session);
+
+    Some(library_input)
+}
+
+fn find_library_file(name: &str, settings: &Settings, session: &mut Session) -> Option<PathBuf> {
+    if settings.autoload_dirs.is_empty() {
+        return None;
+    }
+
+    let index: &HashMap<String, PathBuf> = session.library_index.get_or_insert_with(|| read_library_index(&settings.autoload_dirs));
+    if let Some(path) = index.get(name) {
+        return Some(path.clone());
+    }
+
+    for dir in &settings.autoload_dirs {
+        let path: PathBuf = dir.join(format!("{}.tex", name));
+        if path.is_file() {
+            return Some(path);
+        }
+    }
+
+    None
+}
+
+// merges the index files of all library directories, earlier directories take precedence
+fn read_library_index(dirs: &[PathBuf]) -> HashMap<String, PathBuf> {
+    let mut index: HashMap<String, PathBuf> = HashMap::new();
+
+    for dir in dirs {
+        let contents: String = match fs::read_to_string(dir.join(LIBRARY_INDEX)) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(_) => {
+                die!("Unable to read library index in {}", dir.display());
+            }
+        };
+
+        for line in contents.lines() {
+            let line: &str = line.trim();
+            if line.is_empty() || line.starts_with('%') {
+                continue;
+            }
+
+            match line.split_once('=') {
+                Some((name, file)) if is_macro_name(name.trim()) && !file.trim().is_empty() => {
+                    index.entry(name.trim().to_string()).or_insert_with(|| dir.join(file.trim()));
                 }
-                CommentState::EndComment => {
-                    if c == '%' {
-                        state = CommentState::StartComment;
-                    }
-                    else if c == '\\' {
-                        state = CommentState::Escape;
-                        str.push('\\');
-                    }
-                    else if c != '\t' && c != ' ' {
-                        state = CommentState::PlainText;
-                        str.push(c);
-                    }
+                _ => {
+                    die!("Invalid library index entry: {}", line);
                 }
             }
         }
+    }
+
+    index
+}
+
+fn is_macro_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric())
+}
+
+/* ################################# Dependency Functions ################################# */
+
+impl Session {
+    fn record_file(&mut self, path: &Path) {
+        if self.files_seen.insert(path.to_path_buf()) {
+            self.files_read.push(path.to_path_buf());
+            self.files_modified.push(modified(path));
+        }
+    }
+}
+
+// the -MT target, or else the output file, or else the first input file without its extension
+fn dependency_target(settings: &Settings, inputs: &[InputArg]) -> String {
+    if let Some(target) = &settings.dependency_target {
+        return target.clone();
+    }
+
+    if let Some(output) = &settings.output {
+        return output.to_string_lossy().into_owned();
+    }
+
+    for input_arg in inputs {
+        if let InputArg::File(file) = input_arg {
+            return Path::new(file).with_extension("").to_string_lossy().into_owned();
+        }
+    }
+
+    usage_error("Dependency generation needs -MT, -o or an input file to name the target");
+}
+
+/* ################################# Call Tracking Functions ################################# */
+
+// --trace, --profile, --coverage and the limits follow each call until the text it returned has been read
+impl Session {
+    fn follows_calls(&self) -> bool {
+        self.tracing || self.limited || self.profiler.is_some() || self.coverage.is_some()
+    }
+
+    // the number of calls the traced call is nested in, counted from where tracing was switched on
+    fn trace_depth(&self) -> usize {
+        self.traced_calls.len().saturating_sub(1)
+    }
+
+    // output written so far at the given nesting level, nested levels start from their own empty output
+    fn output_mark(&self, output: &str, level: usize) -> usize {
+        if level == 0 {
+            self.written_output + output.len()
+        } else {
+            output.len()
+        }
+    }
+
+    // the call is shown with the escape character it was made with, an active character has none,
+    // custom tells that name is a macro defined with \def and not a builtin
+    fn enter_call(&mut self, name: &str, escape_char: Option<char>, custom: bool, input: &Input, suspended: &[Frame], output: &str) {
+        let level: usize = suspended.len();
+        let mark: usize = self.output_mark(output, level);
+        let call: String = match escape_char {
+            Some(escape) => format!("{}{}", escape, name),
+            None => name.to_string(),
+        };
+
+        if self.tracing {
+            self.traced_calls.push((level, None));
+        }
+        if self.limited {
+            self.active_calls.push((call.clone(), level, None));
+        }
+        if let Some(coverage) = &mut self.coverage {
+            let conditional: Option<&str> = (escape_char.is_some() && matches!(name, "if" | "ifdef")).then_some(call.as_str());
+            coverage.enter(custom.then_some(name), conditional, current_position(input, suspended), level);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.enter(call, level, mark);
+        }
+    }
+
+    // the current call has read its arguments and is about to put its result in front of the input
+    fn returned(&mut self, input: &Input) {
+        if let Some((_, base)) = self.traced_calls.last_mut() {
+            *base = Some(input.chunk_count());
+        }
+        if let Some((_, _, base)) = self.active_calls.last_mut() {
+            *base = Some(input.chunk_count());
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.returned(input.chunk_count());
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.returned(input.chunk_count());
+        }
+    }
+
+    // the nested expansion at level + 1 was cut short and goes on reading the caller's input at level,
+    // the call that started it lasts until the rest of the expansion has been read
+    fn join_caller(&mut self, input: &Input, output: &str, level: usize) {
+        let chunks: usize = input.chunk_count();
+        let mark: usize = self.output_mark(output, level);
+
+        if let Some((_, base)) = self.traced_calls.iter_mut().rev().find(|(call_level, _)| *call_level == level) {
+            base.get_or_insert(chunks);
+        }
+        for (call_level, _) in self.traced_calls.iter_mut().filter(|(call_level, _)| *call_level > level) {
+            *call_level = level;
+        }
+        if let Some((_, _, base)) = self.active_calls.iter_mut().rev().find(|(_, call_level, _)| *call_level == level) {
+            base.get_or_insert(chunks);
+        }
+        for (_, call_level, _) in self.active_calls.iter_mut().filter(|(_, call_level, _)| *call_level > level) {
+            *call_level = level;
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.join_caller(chunks, level, mark);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.join_caller(chunks, level);
+        }
+    }
+
+    // ends the calls whose result has been read completely
+    fn leave_calls(&mut self, input: &Input, output: &str, level: usize) {
+        let mark: usize = self.output_mark(output, level);
+
+        while let Some(&(call_level, base)) = self.traced_calls.last() {
+            if call_level != level || base.is_none_or(|base| input.chunk_count() > base) {
+                break;
+            }
+            self.traced_calls.pop();
+        }
+        while let Some(&(_, call_level, base)) = self.active_calls.last() {
+            if call_level != level || base.is_none_or(|base| input.chunk_count() > base) {
+                break;
+            }
+            self.active_calls.pop();
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.leave(input.chunk_count(), level, output, mark);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.leave(input.chunk_count(), level);
+        }
+    }
+}
+
+/* ################################# Output Functions ################################# */
+
+// the outermost output is handed to the sink in blocks of this size
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+fn flush_output(output: &mut String, sink: &mut dyn Write, session: &mut Session) {
+    if sink.write_all(output.as_bytes()).is_err() {
+        die!("Unable to write output");
+    }
+
+    if let Some(profiler) = &mut session.profiler {
+        profiler.flushed(output, session.written_output);
+    }
+    session.written_output += output.len();
+    if let Some(debugger) = &mut session.debugger {
+        debugger.record_output(output);
+    }
+    output.clear();
+}
+
+/* ################################# Trace Functions ################################# */
+
+// the file and line being read, from the innermost file that is still open
+fn current_position<'a>(input: &'a Input, suspended: &'a [Frame]) -> Option<(&'a str, usize)> {
+    input.position().or_else(|| suspended.iter().rev().find_map(|frame| frame.input.position()))
+}
 
-        str = str.chars().rev().collect::<String>();
-        input.push_str(&str);
+fn source_position(input: &Input, suspended: &[Frame]) -> String {
+    match current_position(input, suspended) {
+        Some((name, line)) => format!("{}:{}", name, line),
+        None => String::from("<end of input>"),
+    }
+}
 
+/* ################################# Catcode Functions ################################# */
 
-        buffer.clear();
-        str.clear();
+// the CHAR argument of \catcode and \active is a single character, which may be escaped
+fn catcode_target(argument: &str, catcodes: &Catcodes) -> char {
+    let chars: Vec<char> = argument.chars().collect();
+
+    match chars[..] {
+        [c] => c,
+        [escape, c] if catcodes.get(escape) == Catcode::Escape => c,
+        _ => {
+            die!("Invalid catcode character {}", argument);
+        }
     }
 }
 
 /* ################################# State Machine Function ################################# */
 
-fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashMap<String, String>) {
+// the texts a branch of an \if or \ifdef was read from, without the end group character that closed it
+fn branch_trail(input: &mut Input, end_group: char) -> Option<Pieces> {
+    let mut pieces: Pieces = input.take_trail()?;
+    let (_, start, end): &mut (Rc<str>, usize, usize) = pieces.last_mut()?;
+    *end -= end_group.len_utf8();
+    if start == end {
+        pieces.pop();
+    }
+    Some(pieces)
+}
+
+// puts the branch taken by an \if or \ifdef in front of the input, with --coverage it is put back as the
+// texts it was read from, so that the conditionals in it keep their offset in the macro body they came from
+fn push_branch(input: &mut Input, branch: &mut String, trail: Option<Pieces>) {
+    match trail {
+        Some(pieces) if pieces.iter().map(|(_, start, end)| end - start).sum::<usize>() == branch.len() => input.push_pieces(&pieces),
+        _ => input.push_string(mem::take(branch)),
+    }
+}
+
+// the argument that characters other than escape and group characters are appended to unchanged,
+// None for arguments that check every character, like macro names
+fn raw_argument<'a>(macro_state: &MacroState, arg_state: &ArgState, macro_args: &'a mut MacroArgs) -> Option<&'a mut String> {
+    match (macro_state, arg_state) {
+        (MacroState::If | MacroState::ExpandAfter | MacroState::Include | MacroState::IncludeAll | MacroState::Catcode | MacroState::Active | MacroState::Custom, ArgState::Arg1) => Some(&mut macro_args.arg_1),
+        (MacroState::Def | MacroState::If | MacroState::IfDef | MacroState::ExpandAfter | MacroState::Active, ArgState::Arg2) => Some(&mut macro_args.arg_2),
+        (MacroState::If | MacroState::IfDef, ArgState::Arg3) => Some(&mut macro_args.arg_3),
+        _ => None,
+    }
+}
+
+fn state_machine(input: &mut Input, sink: &mut dyn Write, macro_table: &mut MacroTable, catcodes: &mut Catcodes, settings: &Settings, session: &mut Session) {
+    let mut output_buffer: String = String::new();
+    let output: &mut String = &mut output_buffer;
+
     let mut bracket_count: usize = 0;
+    // the escape character that started the current macro name
+    let mut escape_char: char = '\\';
 
     let mut parse_state: ParseState = ParseState::Text;
     let mut macro_state: MacroState = MacroState::Def;
     let mut arg_state: ArgState = ArgState::Arg1;
 
     let mut macro_name: String = String::new();
+    let mut macro_symbol: Symbol = Symbol::default();
 
     let mut macro_args: MacroArgs = MacroArgs {
         arg_1: String::new(),
         arg_2: String::new(),
         arg_3: String::new(),
     };
+    // the texts the branches of an \if or \ifdef were read from, see push_branch
+    let mut branch_trails: [Option<Pieces>; 2] = [None, None];
+
+    // evaluations waiting for a nested \expandafter or autoload expansion, innermost last
+    let mut suspended: Vec<Frame> = Vec::new();
+
+    loop {
+        session.check_sizes(input, output, settings);
+
+        // nested evaluations keep their output, it becomes input again later
+        if suspended.is_empty() && output.len() >= OUTPUT_BUFFER_SIZE {
+            flush_output(output, sink, session);
+        }
+
+        if session.follows_calls() {
+            session.leave_calls(input, output, suspended.len());
+        }
+
+        if input.is_empty(catcodes) {
+            if session.follows_calls() {
+                session.leave_calls(input, output, suspended.len());
+            }
+
+            // a pure macro whose expansion reads past its own arguments is not cached, the expansion
+            // goes on with the caller's input as it would without \pure
+            if !matches!(parse_state, ParseState::Text) && matches!(suspended.last(), Some(Frame { resume: Resume::Memo(_), .. })) {
+                let frame: Frame = suspended.pop().unwrap();
+                session.leave_nested(&frame.input, &frame.output);
+                session.memo.cancel();
+
+                *input = frame.input;
+                let result: String = mem::replace(output, frame.output);
+                session.join_caller(input, output, suspended.len());
+                output.push_str(&result);
+                trace!(session, input, &suspended, "pure expansion reads past its arguments, not cached");
+                continue;
+            }
+
+            if !matches!(parse_state, ParseState::Text) {
+                if matches!(parse_state, ParseState::Backslash) {
+                    output.push(escape_char);
+                    parse_state = ParseState::Text;
+                }
+                else {
+                    die!("Exited on bad parse state");
+                }
+            }
+
+            let frame: Frame = match suspended.pop() {
+                Some(frame) => frame,
+                None => break,
+            };
+
+            session.leave_nested(&frame.input, &frame.output);
+            *input = frame.input;
+            let result: String = mem::replace(output, frame.output);
+
+            match frame.resume {
+                Resume::ExpandAfter(before) => {
+                    trace!(session, input, &suspended, "\\expandafter -> {}{}", before, result);
+                    session.returned(input);
+                    input.push_string(result);
+                    input.push_string(before);
+                }
+
+                Resume::Autoload(name) => {
+                    session.memo.record(&name, macro_table);
+                    if let Some(coverage) = &mut session.coverage {
+                        coverage.autoloaded(&name);
+                    }
+                    macro_symbol = match macro_table.lookup(&name) {
+                        Some(symbol) => symbol,
+                        None => {
+                            die!("Cannot find undefined macro");
+                        }
+                    };
+
+                    // pick the call up again right after its opening brace
+                    macro_name = name;
+                    macro_state = MacroState::Custom;
+                    parse_state = ParseState::Process;
+                    bracket_count = 1;
+                }
+
+                Resume::Memo(key) => {
+                    trace!(session, input, &suspended, "pure result cached: {}", result);
+                    session.memo.finish(key, &result);
+                    session.returned(input);
+                    output.push_str(&result);
+                }
+            }
+
+            continue;
+        }
+
+        // plain text is copied to the output a whole run at a time
+        if matches!(parse_state, ParseState::Text) && input.pop_text_run(output, catcodes) > 0 {
+            continue;
+        }
+
+        // and so are the characters of an argument that are copied as they are
+        if matches!(parse_state, ParseState::Process) {
+            if let Some(argument) = raw_argument(&macro_state, &arg_state, &mut macro_args) {
+                if input.pop_argument_run(argument, catcodes) > 0 {
+                    continue;
+                }
+            }
+        }
 
-    while input.len() > 0 {
-        let mut c: char = input.pop().unwrap();
+        let mut c: char = input.pop(catcodes).unwrap();
 
         match parse_state {
             ParseState::Text => {
-                if c == '\\' {
+                if catcodes.get(c) == Catcode::Escape {
+                    escape_char = c;
                     parse_state = ParseState::Backslash;
+                    if let Some(coverage) = &mut session.coverage {
+                        coverage.escape_read(input.text_origin());
+                    }
+                } else if catcodes.get(c) == Catcode::Active {
+                    // an active character is a call without a name or an argument
+                    session.step(&c.to_string(), None, settings);
+                    if session.debugger.is_some() {
+                        debug::pause(&c.to_string(), None, input, &suspended, output, macro_table, session);
+                    }
+                    if session.follows_calls() {
+                        session.enter_call(&c.to_string(), None, false, input, &suspended, output);
+                    }
+
+                    let body: &Rc<str> = catcodes.active_body(c);
+                    trace!(session, input, &suspended, "{} -> {}", c, body);
+                    session.returned(input);
+                    input.push_shared(body, 0, body.len());
                 } else {
                     output.push(c);
                     parse_state = ParseState::Text;
@@ -162,14 +852,15 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
             }
 
             ParseState::Backslash => {
-                if c == '\\' || c == '#' || c == '%' || c == '{' || c == '}' {
+                // an escaped special character stands for itself
+                if catcodes.get(c) != Catcode::Other {
                     output.push(c);
                     parse_state = ParseState::Text;
                 } else if c.is_alphanumeric() {
                     macro_name.push(c);
                     parse_state = ParseState::Macro;
                 } else {
-                    output.push('\\');
+                    output.push(escape_char);
                     output.push(c);
                     parse_state = ParseState::Text;
                 }
@@ -178,8 +869,12 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
             ParseState::Macro => {
                 if c.is_alphanumeric() {
                     macro_name.push(c);
-                } else if c == '{' {
+                } else if catcodes.get(c) == Catcode::BeginGroup {
                     bracket_count += 1;
+                    session.step(&macro_name, Some(escape_char), settings);
+                    if session.debugger.is_some() {
+                        debug::pause(&macro_name, Some(escape_char), input, &suspended, output, macro_table, session);
+                    }
 
                     if macro_name == "def" {
                         macro_state = MacroState::Def;
@@ -193,13 +888,56 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                         macro_state = MacroState::ExpandAfter;
                     } else if macro_name == "include" {
                         macro_state = MacroState::Include;
+                    } else if macro_name == "includeall" {
+                        macro_state = MacroState::IncludeAll;
+                    } else if macro_name == "pure" {
+                        macro_state = MacroState::Pure;
+                    } else if macro_name == "catcode" {
+                        macro_state = MacroState::Catcode;
+                    } else if macro_name == "active" {
+                        macro_state = MacroState::Active;
+                    } else if macro_name == "traceon" {
+                        macro_state = MacroState::TraceOn;
+                    } else if macro_name == "traceoff" {
+                        macro_state = MacroState::TraceOff;
                     } else {
                         macro_state = MacroState::Custom;
-                        if !macro_hash.contains_key(&macro_name) {
-                            die!("Cannot find undefined macro");
+                        session.memo.record(&macro_name, macro_table);
+
+                        if let Some(symbol) = macro_table.lookup(&macro_name) {
+                            macro_symbol = symbol;
+                        } else {
+                            let library_input: Input = match load_library(&macro_name, settings, session) {
+                                Some(library_input) => library_input,
+                                None => {
+                                    die!("Cannot find undefined macro");
+                                }
+                            };
+
+                            // expand the library first, the call is resumed once it is done
+                            if session.follows_calls() {
+                                session.enter_call(&macro_name, Some(escape_char), true, input, &suspended, output);
+                            }
+                            trace!(session, input, &suspended, "\\{} is undefined, autoloading it", macro_name);
+                            session.memo.side_effect();
+                            session.enter_nested(input, output, settings);
+                            suspended.push(Frame {
+                                input: mem::replace(input, library_input),
+                                output: mem::take(output),
+                                resume: Resume::Autoload(mem::take(&mut macro_name)),
+                            });
+
+                            bracket_count = 0;
+                            parse_state = ParseState::Text;
+                            continue;
                         }
                     }
 
+                    if session.follows_calls() {
+                        let custom: bool = matches!(macro_state, MacroState::Custom);
+                        session.enter_call(&macro_name, Some(escape_char), custom, input, &suspended, output);
+                    }
+
                     parse_state = ParseState::Process;
 
                 } else {
@@ -214,12 +952,12 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                             ArgState::Arg1 => {
                                 if c.is_alphanumeric() {
                                     macro_args.arg_1.push(c);
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
                                     
-                                    if let Some(last_char) = input.chars().last() {
-                                        if last_char == '{' {
-                                            input.pop().unwrap();
+                                    if let Some(last_char) = input.peek(catcodes) {
+                                        if catcodes.get(last_char) == Catcode::BeginGroup {
+                                            input.pop(catcodes).unwrap();
                                             arg_state = ArgState::Arg2;
                                             bracket_count += 1;
                                         } else {
@@ -233,21 +971,30 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                             }
 
                             ArgState::Arg2 => 'def_arg2: {
-                                if c == '\\' {
+                                if catcodes.get(c) == Catcode::Escape {
                                     macro_args.arg_2.push(c);
-                                    c = input.pop().unwrap();
-                                } else if c == '{' {
+                                    if let Some(coverage) = &mut session.coverage {
+                                        coverage.body_escape(macro_args.arg_2.len(), input.reading_position());
+                                    }
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
                                     bracket_count += 1;
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
                                     if bracket_count == 0 {
                                         if macro_args.arg_1.is_empty() {
                                             die!("Empty macro name argument");
-                                        } else if macro_hash.contains_key(&macro_args.arg_1) {
+                                        } else if !macro_table.define(&macro_args.arg_1, MacroDef::new(&macro_args.arg_2, catcodes)) {
                                             die!("Macro already defined");
                                         } else {
-                                            macro_hash.insert(macro_args.arg_1.clone(), macro_args.arg_2.clone());
+                                            trace!(session, input, &suspended, "\\def{{{}}}{{{}}}", macro_args.arg_1, macro_args.arg_2);
+                                            session.returned(input);
+                                            if let Some(coverage) = &mut session.coverage {
+                                                let symbol: Symbol = macro_table.lookup(&macro_args.arg_1).unwrap();
+                                                coverage.define(&macro_args.arg_1, &macro_table.definition(symbol).body, catcodes);
+                                            }
+                                            session.memo.side_effect();
 
                                             macro_name.clear();
                                             macro_args.arg_1.clear();
@@ -273,16 +1020,21 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                             ArgState::Arg1 => {
                                 if c.is_alphanumeric() {
                                     macro_args.arg_1.push(c);
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
                                     if macro_args.arg_1.is_empty() {
                                         die!("Empty macro name argument");
-                                    } else if !macro_hash.contains_key(&macro_args.arg_1) {
+                                    } else if !macro_table.undefine(&macro_args.arg_1) {
                                         die!("Cannot delete undefined macro");
                                     }
 
-                                    macro_hash.remove(&macro_args.arg_1);
+                                    trace!(session, input, &suspended, "\\undef{{{}}}", macro_args.arg_1);
+                                    session.returned(input);
+                                    if let Some(coverage) = &mut session.coverage {
+                                        coverage.undefine(&macro_args.arg_1);
+                                    }
+                                    session.memo.side_effect();
 
                                     macro_name.clear();
                                     macro_args.arg_1.clear();
@@ -306,18 +1058,21 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                     MacroState::If => {
                         match arg_state {
                             ArgState::Arg1 => 'if_arg1: {
-                                if c == '\\' {
+                                if catcodes.get(c) == Catcode::Escape {
                                     macro_args.arg_1.push(c);
-                                    c = input.pop().unwrap();
-                                } else if c == '{' {
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
                                     bracket_count += 1;
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
                                     if bracket_count == 0 {
-                                        if let Some(last_char) = input.chars().last() {
-                                            if last_char == '{' {
-                                                input.pop().unwrap();
+                                        if let Some(last_char) = input.peek(catcodes) {
+                                            if catcodes.get(last_char) == Catcode::BeginGroup {
+                                                input.pop(catcodes).unwrap();
+                                                if session.coverage.is_some() {
+                                                    input.start_trail();
+                                                }
                                                 arg_state = ArgState::Arg2;
                                                 bracket_count += 1;
                                                 break 'if_arg1;
@@ -331,18 +1086,22 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                             }
 
                             ArgState::Arg2 => 'if_arg2: {
-                                if c == '\\' {
+                                if catcodes.get(c) == Catcode::Escape {
                                     macro_args.arg_2.push(c);
-                                    c = input.pop().unwrap();
-                                } else if c == '{' {
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
                                     bracket_count += 1;
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
                                     if bracket_count == 0 {
-                                        if let Some(last_char) = input.chars().last() {
-                                            if last_char == '{' {
-                                                input.pop().unwrap();
+                                        branch_trails[0] = branch_trail(input, c);
+                                        if let Some(last_char) = input.peek(catcodes) {
+                                            if catcodes.get(last_char) == Catcode::BeginGroup {
+                                                input.pop(catcodes).unwrap();
+                                                if session.coverage.is_some() {
+                                                    input.start_trail();
+                                                }
                                                 arg_state = ArgState::Arg3;
                                                 bracket_count += 1;
                                                 break 'if_arg2;
@@ -356,20 +1115,26 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                             }
 
                             ArgState::Arg3 => 'if_arg3: {
-                                if c == '\\' {
+                                if catcodes.get(c) == Catcode::Escape {
                                     macro_args.arg_3.push(c);
-                                    c = input.pop().unwrap();
-                                } else if c == '{' {
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
                                     bracket_count += 1;
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
                                     if bracket_count == 0 {
+                                        branch_trails[1] = branch_trail(input, c);
+                                        session.returned(input);
+                                        if let Some(coverage) = &mut session.coverage {
+                                            coverage.branch(!macro_args.arg_1.is_empty());
+                                        }
+
                                         if !macro_args.arg_1.is_empty() {
-                                            macro_args.arg_2 = macro_args.arg_2.chars().rev().collect::<String>();
-                                            input.push_str(&macro_args.arg_2);
+                                            trace!(session, input, &suspended, "\\if{{{}}} takes the then branch: {}", macro_args.arg_1, macro_args.arg_2);
+                                            push_branch(input, &mut macro_args.arg_2, branch_trails[0].take());
                                         } else {
-                                            macro_args.arg_3 = macro_args.arg_3.chars().rev().collect::<String>();
-                                            input.push_str(&macro_args.arg_3);
+                                            trace!(session, input, &suspended, "\\if{{{}}} takes the else branch: {}", macro_args.arg_1, macro_args.arg_3);
+                                            push_branch(input, &mut macro_args.arg_3, branch_trails[1].take());
                                         }
 
                                         macro_name.clear();
@@ -393,12 +1158,15 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                             ArgState::Arg1 => {
                                 if c.is_alphanumeric() {
                                     macro_args.arg_1.push(c);
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
-                                    if let Some(last_char) = input.chars().last() {
-                                        if last_char == '{' {
-                                            input.pop().unwrap();
+                                    if let Some(last_char) = input.peek(catcodes) {
+                                        if catcodes.get(last_char) == Catcode::BeginGroup {
+                                            input.pop(catcodes).unwrap();
+                                            if session.coverage.is_some() {
+                                                input.start_trail();
+                                            }
                                             arg_state = ArgState::Arg2;
                                             bracket_count += 1;
                                         } else {
@@ -412,18 +1180,22 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                             }
 
                             ArgState::Arg2 => 'ifdef_arg2: {
-                                if c == '\\' {
+                                if catcodes.get(c) == Catcode::Escape {
                                     macro_args.arg_2.push(c);
-                                    c = input.pop().unwrap();
-                                } else if c == '{' {
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
                                     bracket_count += 1;
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
                                     if bracket_count == 0 {
-                                        if let Some(last_char) = input.chars().last() {
-                                            if last_char == '{' {
-                                                input.pop().unwrap();
+                                        branch_trails[0] = branch_trail(input, c);
+                                        if let Some(last_char) = input.peek(catcodes) {
+                                            if catcodes.get(last_char) == Catcode::BeginGroup {
+                                                input.pop(catcodes).unwrap();
+                                                if session.coverage.is_some() {
+                                                    input.start_trail();
+                                                }
                                                 arg_state = ArgState::Arg3;
                                                 bracket_count += 1;
                                                 break 'ifdef_arg2;
@@ -438,21 +1210,28 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                             }
 
                             ArgState::Arg3 => 'ifdef_arg3: {
-                                if c == '\\' {
+                                if catcodes.get(c) == Catcode::Escape {
                                     macro_args.arg_3.push(c);
-                                    c = input.pop().unwrap();
-                                } else if c == '{' {
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
                                     bracket_count += 1;
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
                                     if bracket_count == 0 {
-                                        if macro_hash.contains_key(&macro_args.arg_1) {
-                                            macro_args.arg_2 = macro_args.arg_2.chars().rev().collect::<String>();
-                                            input.push_str(&macro_args.arg_2);
+                                        branch_trails[1] = branch_trail(input, c);
+                                        session.memo.record(&macro_args.arg_1, macro_table);
+                                        session.returned(input);
+                                        if let Some(coverage) = &mut session.coverage {
+                                            coverage.branch(macro_table.is_defined(&macro_args.arg_1));
+                                        }
+
+                                        if macro_table.is_defined(&macro_args.arg_1) {
+                                            trace!(session, input, &suspended, "\\ifdef{{{}}} takes the then branch: {}", macro_args.arg_1, macro_args.arg_2);
+                                            push_branch(input, &mut macro_args.arg_2, branch_trails[0].take());
                                         } else {
-                                            macro_args.arg_3 = macro_args.arg_3.chars().rev().collect::<String>();
-                                            input.push_str(&macro_args.arg_3);
+                                            trace!(session, input, &suspended, "\\ifdef{{{}}} takes the else branch: {}", macro_args.arg_1, macro_args.arg_3);
+                                            push_branch(input, &mut macro_args.arg_3, branch_trails[1].take());
                                         }
 
                                         macro_name.clear();
@@ -474,18 +1253,18 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                     MacroState::ExpandAfter => {
                         match arg_state {
                             ArgState::Arg1 => 'expandafter_arg1: {
-                                if c == '\\' {
+                                if catcodes.get(c) == Catcode::Escape {
                                     macro_args.arg_1.push(c);
-                                    c = input.pop().unwrap();
-                                } else if c == '{' {
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
                                     bracket_count += 1;
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
                                     if bracket_count == 0 {
-                                        if let Some(last_char) = input.chars().last() {
-                                            if last_char == '{' {
-                                                input.pop().unwrap();
+                                        if let Some(last_char) = input.peek(catcodes) {
+                                            if catcodes.get(last_char) == Catcode::BeginGroup {
+                                                input.pop(catcodes).unwrap();
                                                 arg_state = ArgState::Arg2;
                                                 bracket_count += 1;
                                                 break 'expandafter_arg1;
@@ -499,28 +1278,25 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                             }
 
                             ArgState::Arg2 => 'expandafter_arg2: {
-                                if c == '\\' {
+                                if catcodes.get(c) == Catcode::Escape {
                                     macro_args.arg_2.push(c);
-                                    c = input.pop().unwrap();
-                                } else if c == '{' {
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
                                     bracket_count += 1;
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
                                     if bracket_count == 0 {
-                                        let mut temp_1: String = String::new();
-                                        let mut temp_2: String = String::new();
-
-                                        macro_args.arg_2 = macro_args.arg_2.chars().rev().collect::<String>();
-                                        temp_1.push_str(&macro_args.arg_2);
-
-                                        state_machine(&mut temp_1, &mut temp_2, macro_hash);
+                                        // AFTER is expanded on its own input, BEFORE waits in the suspended frame
+                                        trace!(session, input, &suspended, "\\expandafter{{{}}}{{{}}}", macro_args.arg_1, macro_args.arg_2);
+                                        let after: Input = Input::from_string(mem::take(&mut macro_args.arg_2));
 
-                                        temp_2 = temp_2.chars().rev().collect::<String>();
-                                        input.push_str(&temp_2);
-
-                                        macro_args.arg_1 = macro_args.arg_1.chars().rev().collect::<String>();
-                                        input.push_str(&macro_args.arg_1);
+                                        session.enter_nested(input, output, settings);
+                                        suspended.push(Frame {
+                                            input: mem::replace(input, after),
+                                            output: mem::take(output),
+                                            resume: Resume::ExpandAfter(mem::take(&mut macro_args.arg_1)),
+                                        });
 
                                         macro_name.clear();
                                         macro_args.arg_1.clear();
@@ -543,16 +1319,20 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                     MacroState::Include => 'include_arg1: {
                         match arg_state {
                             ArgState::Arg1 => {
-                                if c == '\\' {
+                                if catcodes.get(c) == Catcode::Escape {
                                     macro_args.arg_1.push(c);
-                                    c = input.pop().unwrap();
-                                } else if c == '{' {
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
                                     bracket_count += 1;
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
                                     if bracket_count == 0 {
-                                        strip_comments(input, Some(&macro_args.arg_1));
+                                        let path: PathBuf = resolve_include(&macro_args.arg_1, settings);
+                                        trace!(session, input, &suspended, "\\include{{{}}} reads {}", macro_args.arg_1, path.display());
+                                        session.returned(input);
+                                        include_file(input, &path, settings, session);
+                                        session.memo.side_effect();
 
                                         macro_name.clear();
                                         macro_args.arg_1.clear();
@@ -574,42 +1354,284 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
                         }
                     }
 
+                    MacroState::IncludeAll => 'includeall_arg1: {
+                        match arg_state {
+                            ArgState::Arg1 => {
+                                if catcodes.get(c) == Catcode::Escape {
+                                    macro_args.arg_1.push(c);
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
+                                    bracket_count += 1;
+                                } else if catcodes.get(c) == Catcode::EndGroup {
+                                    bracket_count -= 1;
+
+                                    if bracket_count == 0 {
+                                        let files: Vec<PathBuf> = resolve_include_all(&macro_args.arg_1, settings);
+                                        if files.is_empty() && !settings.lenient_include_all {
+                                            die!("No files match include pattern");
+                                        }
+
+                                        trace!(session, input, &suspended, "\\includeall{{{}}} reads {} files", macro_args.arg_1, files.len());
+                                        session.returned(input);
+
+                                        // the input is a reversed stack, so the last file is pushed first
+                                        for file in files.iter().rev() {
+                                            include_file(input, file, settings, session);
+                                        }
+                                        session.memo.side_effect();
+
+                                        macro_name.clear();
+                                        macro_args.arg_1.clear();
+                                        macro_args.arg_2.clear();
+                                        macro_args.arg_3.clear();
+
+                                        arg_state = ArgState::Arg1;
+                                        parse_state = ParseState::Text;
+
+                                        break 'includeall_arg1;
+                                    }
+                                }
+                                macro_args.arg_1.push(c);
+                            }
+
+                            ArgState::Arg2 => {}
+
+                            ArgState::Arg3 => {}
+                        }
+                    }
+
+                    MacroState::Pure => {
+                        match arg_state {
+                            ArgState::Arg1 => {
+                                if c.is_alphanumeric() {
+                                    macro_args.arg_1.push(c);
+                                } else if catcodes.get(c) == Catcode::EndGroup {
+                                    bracket_count -= 1;
+
+                                    if macro_args.arg_1.is_empty() {
+                                        die!("Empty macro name argument");
+                                    } else if !macro_table.set_pure(&macro_args.arg_1) {
+                                        die!("Cannot declare undefined macro pure");
+                                    }
+
+                                    trace!(session, input, &suspended, "\\pure{{{}}}", macro_args.arg_1);
+                                    session.returned(input);
+
+                                    macro_name.clear();
+                                    macro_args.arg_1.clear();
+                                    macro_args.arg_2.clear();
+                                    macro_args.arg_3.clear();
+
+                                    arg_state = ArgState::Arg1;
+                                    parse_state = ParseState::Text;
+
+                                } else {
+                                    die!("Invalid argument provided");
+                                }
+                            }
+
+                            ArgState::Arg2 => {}
+
+                            ArgState::Arg3 => {}
+                        }
+                    }
+
+                    MacroState::Catcode => {
+                        match arg_state {
+                            ArgState::Arg1 => 'catcode_arg1: {
+                                if catcodes.get(c) == Catcode::Escape {
+                                    macro_args.arg_1.push(c);
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
+                                    bracket_count += 1;
+                                } else if catcodes.get(c) == Catcode::EndGroup {
+                                    bracket_count -= 1;
+
+                                    if bracket_count == 0 {
+                                        if let Some(last_char) = input.peek(catcodes) {
+                                            if catcodes.get(last_char) == Catcode::BeginGroup {
+                                                input.pop(catcodes).unwrap();
+                                                arg_state = ArgState::Arg2;
+                                                bracket_count += 1;
+                                                break 'catcode_arg1;
+                                            } else {
+                                                die!("Invalid argument provided");
+                                            }
+                                        }
+                                    }
+                                }
+                                macro_args.arg_1.push(c);
+                            }
+
+                            ArgState::Arg2 => {
+                                if c.is_alphanumeric() {
+                                    macro_args.arg_2.push(c);
+                                } else if catcodes.get(c) == Catcode::EndGroup {
+                                    bracket_count -= 1;
+
+                                    let target: char = catcode_target(&macro_args.arg_1, catcodes);
+                                    let catcode: Catcode = match Catcode::parse(&macro_args.arg_2) {
+                                        Some(catcode) => catcode,
+                                        None => {
+                                            die!("Invalid catcode {}", macro_args.arg_2);
+                                        }
+                                    };
+
+                                    if !catcodes.set(target, catcode) {
+                                        die!("Catcodes can only be assigned to ASCII characters");
+                                    }
+
+                                    trace!(session, input, &suspended, "\\catcode{{{}}}{{{}}}", target, macro_args.arg_2);
+                                    session.returned(input);
+
+                                    // cached expansions were read with the old catcodes
+                                    session.memo.side_effect();
+                                    session.memo.clear();
+
+                                    macro_name.clear();
+                                    macro_args.arg_1.clear();
+                                    macro_args.arg_2.clear();
+                                    macro_args.arg_3.clear();
+
+                                    arg_state = ArgState::Arg1;
+                                    parse_state = ParseState::Text;
+
+                                } else {
+                                    die!("Invalid argument provided");
+                                }
+                            }
+
+                            ArgState::Arg3 => {}
+                        }
+                    }
+
+                    MacroState::Active => {
+                        match arg_state {
+                            ArgState::Arg1 => 'active_arg1: {
+                                if catcodes.get(c) == Catcode::Escape {
+                                    macro_args.arg_1.push(c);
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
+                                    bracket_count += 1;
+                                } else if catcodes.get(c) == Catcode::EndGroup {
+                                    bracket_count -= 1;
+
+                                    if bracket_count == 0 {
+                                        if let Some(last_char) = input.peek(catcodes) {
+                                            if catcodes.get(last_char) == Catcode::BeginGroup {
+                                                input.pop(catcodes).unwrap();
+                                                arg_state = ArgState::Arg2;
+                                                bracket_count += 1;
+                                                break 'active_arg1;
+                                            } else {
+                                                die!("Invalid argument provided");
+                                            }
+                                        }
+                                    }
+                                }
+                                macro_args.arg_1.push(c);
+                            }
+
+                            ArgState::Arg2 => 'active_arg2: {
+                                if catcodes.get(c) == Catcode::Escape {
+                                    macro_args.arg_2.push(c);
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
+                                    bracket_count += 1;
+                                } else if catcodes.get(c) == Catcode::EndGroup {
+                                    bracket_count -= 1;
+
+                                    if bracket_count == 0 {
+                                        let target: char = catcode_target(&macro_args.arg_1, catcodes);
+                                        if !catcodes.set_active(target, Rc::from(macro_args.arg_2.as_str())) {
+                                            die!("Catcodes can only be assigned to ASCII characters");
+                                        }
+
+                                        trace!(session, input, &suspended, "\\active{{{}}}{{{}}}", target, macro_args.arg_2);
+                                        session.returned(input);
+
+                                        // cached expansions were read with the old catcodes
+                                        session.memo.side_effect();
+                                        session.memo.clear();
+
+                                        macro_name.clear();
+                                        macro_args.arg_1.clear();
+                                        macro_args.arg_2.clear();
+                                        macro_args.arg_3.clear();
+
+                                        arg_state = ArgState::Arg1;
+                                        parse_state = ParseState::Text;
+
+                                        break 'active_arg2;
+                                    }
+                                }
+                                macro_args.arg_2.push(c);
+                            }
+
+                            ArgState::Arg3 => {}
+                        }
+                    }
+
+                    MacroState::TraceOn | MacroState::TraceOff => {
+                        if catcodes.get(c) == Catcode::EndGroup {
+                            bracket_count -= 1;
+
+                            session.tracing = matches!(macro_state, MacroState::TraceOn);
+                            if !session.tracing {
+                                session.traced_calls.clear();
+                            }
+                            trace!(session, input, &suspended, "\\traceon");
+                            session.returned(input);
+
+                            macro_name.clear();
+                            parse_state = ParseState::Text;
+                        } else {
+                            die!("Invalid argument provided");
+                        }
+                    }
+
                     MacroState::Custom => {
                         match arg_state {
                             ArgState::Arg1 => 'custom_arg1: {
-                                if c == '\\' {
+                                if catcodes.get(c) == Catcode::Escape {
                                     macro_args.arg_1.push(c);
-                                    c = input.pop().unwrap();
-                                } else if c == '{' {
+                                    c = input.pop(catcodes).unwrap();
+                                } else if catcodes.get(c) == Catcode::BeginGroup {
                                     bracket_count += 1;
-                                } else if c == '}' {
+                                } else if catcodes.get(c) == Catcode::EndGroup {
                                     bracket_count -= 1;
 
                                     if bracket_count == 0 {
-                                        let value: &String = macro_hash.get(&macro_name).unwrap();
-                                        let mut process: String = String::new();
-                                        let mut escape_flag: bool = false;
-
-                                        for i in value.chars() {
-                                            if escape_flag == true {
-                                                process.push(i);
-                                                escape_flag = false;
-                                            } else if i == '\\' {
-                                                process.push(i);
-                                                escape_flag = true;
-                                            } else if i == '#' {
-                                                for j in macro_args.arg_1.chars() {
-                                                    process.push(j)
-                                                }
+                                        let value: &MacroDef = macro_table.definition(macro_symbol);
+                                        let argument: Rc<str> = Rc::from(macro_args.arg_1.as_str());
+
+                                        if !value.pure {
+                                            trace!(session, input, &suspended, "\\{}{{{}}} -> {}", macro_name, macro_args.arg_1, value.substitute(&macro_args.arg_1));
+                                            session.returned(input);
+                                            input.push_expansion(&value.body, &value.params, &argument);
+                                        } else {
+                                            let key: MemoKey = MemoKey::new(macro_symbol, macro_table, &macro_args.arg_1);
+
+                                            if let Some(result) = session.memo.get(&key, macro_table) {
+                                                trace!(session, input, &suspended, "\\{}{{{}}} -> {} (cached)", macro_name, macro_args.arg_1, result);
+                                                session.returned(input);
+                                                output.push_str(&result);
                                             } else {
-                                                process.push(i);
+                                                trace!(session, input, &suspended, "\\{}{{{}}} -> {}", macro_name, macro_args.arg_1, value.substitute(&macro_args.arg_1));
+                                                // the call is expanded on its own so that its result can be cached
+                                                let mut memo_input: Input = Input::default();
+                                                memo_input.push_expansion(&value.body, &value.params, &argument);
+
+                                                session.memo.begin();
+                                                session.enter_nested(input, output, settings);
+                                                suspended.push(Frame {
+                                                    input: mem::replace(input, memo_input),
+                                                    output: mem::take(output),
+                                                    resume: Resume::Memo(key),
+                                                });
                                             }
                                         }
 
-                                        process = process.chars().rev().collect::<String>();
-                                        input.push_str(&process);
-
-                                        process.clear();
                                         macro_name.clear();
                                         macro_args.arg_1.clear();
                                         macro_args.arg_2.clear();
@@ -634,36 +1656,776 @@ fn state_machine(input: &mut String, output: &mut String, macro_hash: &mut HashM
         }
     }
 
-    if !matches!(parse_state, ParseState::Text) {
-        if matches!(parse_state, ParseState::Backslash) {
-            output.push('\\');
+    flush_output(output, sink, session);
+
+    if sink.flush().is_err() {
+        die!("Unable to write output");
+    }
+}
+
+/* ################################# Macro Dump Function ################################# */
+
+// lists the macros still defined at the end as \def lines, in definition order
+fn dump_macros(macro_table: &MacroTable) {
+    for (name, definition) in macro_table.iter() {
+        eprintln!("\\def{{{}}}{{{}}}", name, definition.body);
+    }
+}
+
+/* ################################# Argument Parsing Functions ################################# */
+
+const USAGE: &str = "\
+Usage: rust_macro_processor [OPTION]... [FILE]...
+Expands the macros in the FILEs, read in order, and writes the result to stdout.
+With no FILE, or when FILE is -, standard input is read.
+
+Options:
+  -o, --output FILE             write the output to FILE instead of stdout
+  -I, --include-dir DIR         search DIR for relative \\include and \\includeall paths
+  -D, --define NAME[=VALUE]     define the macro NAME before reading the input
+  -U, --undefine NAME           remove a definition given before
+      --defs FILE               define the macros of a NAME=VALUE or JSON file
+  -e, --eval TEXT               read TEXT as input, in order with the FILEs
+  -M                            write a make rule for the output instead of the output
+  -MD                           write a make rule for the output as well
+  -MF FILE                      write the make rule to FILE
+  -MT TARGET                    name the target of the make rule
+      --autoload DIR            load undefined macros from DIR/NAME.tex
+      --sandbox DIR             restrict included files to DIR
+      --lenient-includeall      allow \\includeall patterns that match no file
+      --max-include-size BYTES  limit the size of each included file
+      --max-steps COUNT         limit the number of macro expansions
+      --max-depth COUNT         limit the nesting of eager expansions
+      --max-output BYTES        limit the size of the output
+      --max-input BYTES         limit the size of the pending input
+      --spool memory|file       hold the output back until the expansion succeeded
+      --newline lf|crlf|preserve
+                                line endings written to the output
+      --input-encoding NAME     utf-8, latin-1, utf-16, utf-16le or utf-16be
+      --catcode CHAR=ROLE       give CHAR a role (escape, begingroup, endgroup,
+                                parameter, comment or other)
+      --dump-macros             print the macros defined at the end to stderr
+      --trace                   log every expansion step to stderr
+      --debug                   stop before macro calls and read debugger commands from stdin
+      --profile                 print call counts and times per macro to stderr
+      --profile-stacks FILE     write the time per call stack to FILE, for flame graphs
+      --coverage FILE           write which definitions were called and which branches
+                                were taken to FILE
+      --coverage-format text|lcov
+                                format of the coverage report
+      --watch                   expand again whenever a file that was read changes
+      --repl                    expand lines typed on the terminal, after the inputs
+      --                        treat all following arguments as files
+  -h, --help                    print this help and exit
+  -V, --version                 print the version and exit
+";
+
+// an input named on the command line
+#[derive(Debug, PartialEq)]
+enum InputArg {
+    File(String),
+    Stdin,
+    Text(String),
+}
+
+// command line mistakes exit with status 2, unlike errors in the input
+fn usage_error(message: &str) -> ! {
+    eprintln!("Rust Macro Processor: {}", message);
+    eprintln!("Try 'rust_macro_processor --help' for more information.");
+    process::exit(2);
+}
+
+// splits the command line into settings and inputs
+fn parse_args(args: &[String], settings: &mut Settings) -> Vec<InputArg> {
+    let mut inputs: Vec<InputArg> = Vec::new();
+    let mut args = args.iter().skip(1);
+    let mut options_ended: bool = false;
+
+    while let Some(arg) = args.next() {
+        if options_ended || arg == "-" || !arg.starts_with('-') {
+            if arg == "-" && !options_ended {
+                inputs.push(InputArg::Stdin);
+            } else {
+                inputs.push(InputArg::File(arg.clone()));
+            }
+            continue;
+        }
+
+        if arg == "--" {
+            options_ended = true;
+            continue;
+        }
+
+        // "--name=value" and "-xvalue" carry their value, otherwise it is the next argument
+        let (option, inline): (&str, Option<&str>) = if arg.starts_with("--") {
+            match arg.split_once('=') {
+                Some((option, value)) => (option, Some(value)),
+                None => (arg.as_str(), None),
+            }
+        } else {
+            // the make dependency options are the only short options longer than one letter
+            let split: usize = if ["-MD", "-MF", "-MT"].iter().any(|option| arg.starts_with(option)) {
+                3
+            } else {
+                1 + arg[1..].chars().next().unwrap().len_utf8()
+            };
+            match &arg[split..] {
+                "" => (&arg[..split], None),
+                value => (&arg[..split], Some(value)),
+            }
+        };
+
+        let mut value = || -> String {
+            match inline {
+                Some(value) => value.to_string(),
+                None => match args.next() {
+                    Some(value) => value.clone(),
+                    None => usage_error(&format!("Option {} requires a value", option)),
+                },
+            }
+        };
+
+        match option {
+            "-h" | "--help" | "-V" | "--version" | "-M" | "-MD" | "--lenient-includeall" | "--dump-macros" | "--watch" | "--repl" | "--trace" | "--debug" | "--profile" if inline.is_some() => {
+                usage_error(&format!("Option {} takes no value", option));
+            }
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                process::exit(0);
+            }
+            "-V" | "--version" => {
+                println!("rust_macro_processor {}", env!("CARGO_PKG_VERSION"));
+                process::exit(0);
+            }
+            "--lenient-includeall" => settings.lenient_include_all = true,
+            "--dump-macros" => settings.dump_macros = true,
+            "--watch" => settings.watch = true,
+            "--repl" => settings.repl = true,
+            "--trace" => settings.trace = true,
+            "--debug" => settings.debug = true,
+            "--profile" => settings.profile = true,
+            "-o" | "--output" => settings.output = Some(PathBuf::from(value())),
+            "-I" | "--include-dir" => settings.include_dirs.push(PathBuf::from(value())),
+            "-D" | "--define" => {
+                let definition: String = value();
+                let (name, body): (&str, &str) = definition.split_once('=').unwrap_or((&definition, ""));
+
+                if !is_macro_name(name) {
+                    usage_error(&format!("Invalid macro name {}", name));
+                }
+                settings.defines.push((name.to_string(), Some(body.to_string())));
+            }
+            "-U" | "--undefine" => {
+                let name: String = value();
+                if !is_macro_name(&name) {
+                    usage_error(&format!("Invalid macro name {}", name));
+                }
+                settings.defines.push((name, None));
+            }
+            "--defs" => {
+                let file: String = value();
+                let text: String = match fs::read_to_string(&file) {
+                    Ok(text) => text,
+                    Err(_) => usage_error(&format!("Unable to read definitions file {}", file)),
+                };
+
+                let definitions: Vec<(String, String)> = match defs::parse(&text, &file) {
+                    Ok(definitions) => definitions,
+                    Err(message) => usage_error(&message),
+                };
+
+                for (name, body) in definitions {
+                    if !is_macro_name(&name) {
+                        usage_error(&format!("Invalid macro name {} in {}", name, file));
+                    }
+                    settings.defines.push((name, Some(body)));
+                }
+            }
+            "-e" | "--eval" => inputs.push(InputArg::Text(value())),
+            "-M" => settings.dependencies = Dependencies::Only,
+            "-MD" => settings.dependencies = Dependencies::WithOutput,
+            "-MF" => settings.dependency_file = Some(PathBuf::from(value())),
+            "--profile-stacks" => settings.profile_stacks = Some(PathBuf::from(value())),
+            "--coverage" => settings.coverage = Some(PathBuf::from(value())),
+            "--coverage-format" => {
+                let name: String = value();
+                settings.coverage_format = match coverage::Format::parse(&name) {
+                    Some(format) => format,
+                    None => usage_error(&format!("Unknown coverage format {}", name)),
+                };
+            }
+            "-MT" => settings.dependency_target = Some(value()),
+            "--autoload" => settings.autoload_dirs.push(PathBuf::from(value())),
+            "--sandbox" => {
+                let dir: String = value();
+                match fs::canonicalize(&dir) {
+                    Ok(root) if root.is_dir() => settings.sandbox_root = Some(root),
+                    _ => usage_error(&format!("Invalid sandbox directory {}", dir)),
+                }
+            }
+            "--max-include-size" => settings.max_include_size = Some(parse_number(&value())),
+            "--max-steps" => settings.max_steps = Some(parse_number(&value())),
+            "--max-depth" => settings.max_depth = Some(parse_number(&value())),
+            "--max-output" => settings.max_output = Some(parse_number(&value())),
+            "--max-input" => settings.max_input = Some(parse_number(&value())),
+            "--spool" => {
+                settings.spool = match value().as_str() {
+                    "memory" => Spool::Memory,
+                    "file" => Spool::File,
+                    mode => usage_error(&format!("Invalid spool mode {}", mode)),
+                };
+            }
+            "--newline" => {
+                settings.newline = match value().as_str() {
+                    "lf" => Newline::Lf,
+                    "crlf" => Newline::CrLf,
+                    "preserve" => Newline::Preserve,
+                    mode => usage_error(&format!("Invalid newline mode {}", mode)),
+                };
+            }
+            "--catcode" => {
+                // CHAR=NAME, the character itself may be '='
+                let assignment: String = value();
+                let mut chars = assignment.chars();
+                let target: Option<char> = chars.next();
+                let catcode: Option<Catcode> = chars.as_str().strip_prefix('=').and_then(Catcode::parse);
+
+                match (target, catcode) {
+                    (Some(target), Some(catcode)) if settings.catcodes.set(target, catcode) => {}
+                    _ => usage_error(&format!("Invalid catcode assignment {}", assignment)),
+                }
+            }
+            "--input-encoding" => {
+                let name: String = value();
+                settings.input_encoding = match Encoding::parse(&name) {
+                    Some(encoding) => encoding,
+                    None => usage_error(&format!("Unknown input encoding {}", name)),
+                };
+            }
+            _ => usage_error(&format!("Unknown option {}", option)),
+        }
+    }
+
+    if settings.sandbox_root.is_some() && settings.max_include_size.is_none() {
+        settings.max_include_size = Some(DEFAULT_SANDBOX_MAX_SIZE);
+    }
+
+    // the REPL reads stdin itself
+    if settings.repl {
+        if inputs.iter().any(|input_arg| matches!(input_arg, InputArg::Stdin)) {
+            usage_error("--repl cannot read standard input as an input file");
+        }
+        if settings.output.is_some() || settings.watch || settings.dependencies != Dependencies::Off || settings.profile || settings.profile_stacks.is_some() || settings.coverage.is_some() {
+            usage_error("--repl cannot be combined with -o, -M, -MD, --watch, --profile or --coverage");
+        }
+    } else if inputs.is_empty() {
+        inputs.push(InputArg::Stdin);
+    }
+
+    // the debugger reads its commands from stdin too
+    if settings.debug {
+        if settings.repl || settings.watch {
+            usage_error("--debug cannot be combined with --repl or --watch");
         }
-        else {
-            die!("Exited on bad parse state");
+        if inputs.iter().any(|input_arg| matches!(input_arg, InputArg::Stdin)) {
+            usage_error("--debug reads its commands from standard input, give the input as files or with -e");
         }
     }
+
+    inputs
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> T {
+    match value.parse::<T>() {
+        Ok(number) => number,
+        Err(_) => usage_error(&format!("Invalid number {}", value)),
+    }
 }
 
 /* ################################# Main Function ################################# */
 
+// puts the inputs named on the command line in front of the input
+fn push_inputs(input: &mut Input, inputs: &[InputArg], settings: &Settings) {
+    // the input is a reversed stack, so the last input is pushed first
+    for input_arg in inputs.iter().rev() {
+        match input_arg {
+            InputArg::File(file) => strip_comments(input, Some(file), settings.input_encoding),
+            InputArg::Stdin => strip_comments(input, None, settings.input_encoding),
+            InputArg::Text(text) => {
+                input.push_source(Source::new(Box::new(io::Cursor::new(text.clone().into_bytes())), "<command line>", None, Encoding::Utf8));
+            }
+        }
+    }
+}
+
+// applies -D, -U and --defs, later definitions of the same name replace earlier ones
+fn predefine(macro_table: &mut MacroTable, settings: &Settings, catcodes: &Catcodes) {
+    for (name, body) in &settings.defines {
+        macro_table.undefine(name);
+        if let Some(body) = body {
+            macro_table.define(name, MacroDef::new(body, catcodes));
+        }
+    }
+}
+
+// how often --watch looks at the modification times
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+// expands the inputs once, the files read are recorded in the session
+fn run(inputs: &[InputArg], settings: &Settings, session: &mut Session) {
+    let mut input: Input = Input::default();
+    let mut macro_table: MacroTable = MacroTable::default();
+    session.tracing = settings.trace;
+    session.limited = has_limits(settings);
+    if settings.debug {
+        session.debugger = Some(Debugger::new());
+    }
+    if settings.profile || settings.profile_stacks.is_some() {
+        session.profiler = Some(Profiler::new());
+    }
+    if settings.coverage.is_some() {
+        session.coverage = Some(Coverage::default());
+    }
+
+    let target_name: Option<String> = match settings.dependencies {
+        Dependencies::Off => None,
+        _ => Some(dependency_target(settings, inputs)),
+    };
+
+    for input_arg in inputs {
+        if let InputArg::File(file) = input_arg {
+            session.record_file(Path::new(file));
+        }
+    }
+
+    push_inputs(&mut input, inputs, settings);
+
+    // \catcode changes start from the catcodes given on the command line
+    let mut catcodes: Catcodes = settings.catcodes.clone();
+    predefine(&mut macro_table, settings, &catcodes);
+
+    // an output file is written next to its final name and only renamed to it on success,
+    // so an error leaves the previous output in place
+    let mut output_file: Option<TempFile> = match &settings.output {
+        Some(_) if settings.dependencies == Dependencies::Only => None,
+        Some(path) => {
+            let dir: &Path = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+
+            match TempFile::create(dir) {
+                Ok(file) => Some(file),
+                Err(_) => {
+                    die!("Unable to create output file {}", path.display());
+                }
+            }
+        }
+        None => None,
+    };
+
+    let target: Box<dyn Write + '_> = match &mut output_file {
+        Some(file) => Box::new(file.file()),
+        // -M only expands the input to find the files it reads
+        None if settings.dependencies == Dependencies::Only => Box::new(io::sink()),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut target: NewlineWriter<Box<dyn Write + '_>> = NewlineWriter::new(target, settings.newline);
+
+    // without a spool the output is streamed, otherwise it is only released on success
+    match settings.spool {
+        Spool::Off => {
+            state_machine(&mut input, &mut target, &mut macro_table, &mut catcodes, settings, session);
+        }
+        Spool::Memory => {
+            let mut spool: Vec<u8> = Vec::new();
+            state_machine(&mut input, &mut spool, &mut macro_table, &mut catcodes, settings, session);
+
+            if target.write_all(&spool).and_then(|_| target.flush()).is_err() {
+                die!("Unable to write output");
+            }
+        }
+        Spool::File => {
+            let mut spool: TempFile = match TempFile::create(&env::temp_dir()) {
+                Ok(spool) => spool,
+                Err(_) => {
+                    die!("Unable to create spool file");
+                }
+            };
+            state_machine(&mut input, spool.file(), &mut macro_table, &mut catcodes, settings, session);
+
+            if spool.copy_to(&mut target).and_then(|_| target.flush()).is_err() {
+                die!("Unable to write output");
+            }
+        }
+    }
+
+    drop(target);
+    if let (Some(file), Some(path)) = (output_file, &settings.output) {
+        if file.persist(path).is_err() {
+            die!("Unable to write output file {}", path.display());
+        }
+    }
+
+    if let Some(target_name) = target_name {
+        let rule: String = depend::make_rule(&target_name, &session.files_read);
+
+        // -M writes to stdout unless -MF is given, -MD defaults to the target with a .d extension
+        let dependency_file: Option<PathBuf> = match (&settings.dependency_file, settings.dependencies) {
+            (Some(file), _) => Some(file.clone()),
+            (None, Dependencies::WithOutput) => Some(Path::new(&target_name).with_extension("d")),
+            (None, _) => None,
+        };
+
+        let written: io::Result<()> = match &dependency_file {
+            Some(file) => fs::write(file, rule),
+            None => io::stdout().write_all(rule.as_bytes()),
+        };
+        if written.is_err() {
+            die!("Unable to write dependencies");
+        }
+    }
+
+    if settings.dump_macros {
+        dump_macros(&macro_table);
+    }
+
+    if let Some(profiler) = &session.profiler {
+        if settings.profile {
+            eprint!("{}", profiler.report());
+        }
+        if let Some(path) = &settings.profile_stacks {
+            if fs::write(path, profiler.collapsed_stacks()).is_err() {
+                die!("Unable to write profile stacks to {}", path.display());
+            }
+        }
+    }
+
+    if let (Some(coverage), Some(path)) = (&session.coverage, &settings.coverage) {
+        if fs::write(path, coverage.report(settings.coverage_format)).is_err() {
+            die!("Unable to write coverage report to {}", path.display());
+        }
+    }
+}
+
+// runs the expansion again whenever one of the files it read changes, errors are
+// reported but do not end the loop
+fn watch(inputs: &[InputArg], settings: &Settings) -> ! {
+    if inputs.iter().any(|input_arg| matches!(input_arg, InputArg::Stdin)) {
+        usage_error("--watch cannot read standard input");
+    }
+
+    loop {
+        let mut session: Session = Session::default();
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| run(inputs, settings, &mut session)));
+
+        // the files read before an error are watched too, a file changed while it was expanded is expanded again
+        let snapshot: Vec<(PathBuf, Option<SystemTime>)> = session.files_read.into_iter().zip(session.files_modified).collect();
+        // only -e text that includes nothing reads no files, and then nothing could ever change
+        if snapshot.is_empty() {
+            die!("--watch found no files to watch, the input read none");
+        }
+
+        let files: &str = if snapshot.len() == 1 { "file" } else { "files" };
+        eprintln!("Rust Macro Processor: Watching {} {} for changes", snapshot.len(), files);
+
+        while snapshot.iter().all(|(path, time)| modified(path) == *time) {
+            thread::sleep(WATCH_INTERVAL);
+        }
+    }
+}
+
+// None for files that cannot be read, so that creating them counts as a change
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
 fn main() {
     panic::set_hook(Box::new(|_| {}));
 
-    let mut input: String = String::new();
-    let mut output: String = String::new();
-    let mut macro_hash: HashMap<String, String> = HashMap::new();
+    let mut settings: Settings = Settings::default();
 
     let args: Vec<String> = env::args().collect();
+    let inputs: Vec<InputArg> = parse_args(&args, &mut settings);
+
+    if settings.repl {
+        repl::repl(&settings, &inputs);
+        return;
+    }
+
+    if settings.watch {
+        watch(&inputs, &settings);
+    }
+
+    let mut session: Session = Session::default();
+    run(&inputs, &settings, &mut session);
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::AssertUnwindSafe;
+
+    // an empty directory below the system temporary directory, unique to the test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir: PathBuf = env::temp_dir().join(format!("rust_macro_processor_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(&dir).unwrap()
+    }
+
+    fn fails<T>(f: impl FnOnce() -> T) -> bool {
+        panic::catch_unwind(AssertUnwindSafe(f)).is_err()
+    }
 
-    if args.len() == 1 {
-        strip_comments(&mut input, None);
-    } else {
-        for i in (1..args.len()).rev() {
-            strip_comments(&mut input, Some(&args[i]));
+    #[test]
+    fn watch_needs_a_file() {
+        let settings: Settings = Settings::default();
+        assert!(fails(|| watch(&[InputArg::Text(String::new())], &settings)));
+    }
+
+    #[test]
+    fn sandbox_confines_includes() {
+        let dir: PathBuf = temp_dir("sandbox");
+        let root: PathBuf = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/inner.tex"), "inner").unwrap();
+        fs::write(dir.join("outside.tex"), "outside").unwrap();
+
+        let settings: Settings = Settings {
+            sandbox_root: Some(root.clone()),
+            ..Settings::default()
+        };
+
+        // paths below the root are read
+        assert_eq!(resolve_include("sub/inner.tex", &settings), root.join("sub/inner.tex"));
+        assert!(!fails(|| open_sandboxed(&resolve_include("sub/inner.tex", &settings), &root)));
+        assert_eq!(resolve_include_all("sub/*.tex", &settings), vec![root.join("sub/inner.tex")]);
+
+        // absolute paths and '..' are refused before the file system is looked at
+        assert!(fails(|| resolve_include("/etc/passwd", &settings)));
+        assert!(fails(|| resolve_include_all("/etc/*", &settings)));
+        assert!(fails(|| resolve_include("../outside.tex", &settings)));
+        assert!(fails(|| resolve_include("sub/../sub/inner.tex", &settings)));
+        assert!(fails(|| resolve_include_all("../*.tex", &settings)));
+        assert!(fails(|| resolve_include_all("sub/../../*", &settings)));
+
+        // as is anything but a regular file
+        assert!(fails(|| open_sandboxed(&resolve_include("sub", &settings), &root)));
+        assert!(fails(|| open_sandboxed(&resolve_include("missing.tex", &settings), &root)));
+        assert!(fails(|| open_sandboxed(&dir.join("outside.tex"), &root)));
+
+        #[cfg(unix)]
+        {
+            // symlinks out of the root are neither read nor listed
+            std::os::unix::fs::symlink(dir.join("outside.tex"), root.join("link.tex")).unwrap();
+            std::os::unix::fs::symlink(&dir, root.join("up")).unwrap();
+            assert!(fails(|| open_sandboxed(&resolve_include("link.tex", &settings), &root)));
+            assert!(fails(|| open_sandboxed(&resolve_include("up/outside.tex", &settings), &root)));
+            assert_eq!(resolve_include_all("up/*.tex", &settings), Vec::<PathBuf>::new());
+            assert_eq!(resolve_include_all("*/inner.tex", &settings), vec![root.join("sub/inner.tex")]);
         }
+
+        // libraries are subject to the sandbox too
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/outer.tex"), "\\def{outer}{}").unwrap();
+        fs::write(root.join("sub/inside.tex"), "\\def{inside}{}").unwrap();
+        let settings: Settings = Settings {
+            sandbox_root: Some(root.clone()),
+            autoload_dirs: vec![dir.join("lib"), root.join("sub")],
+            ..Settings::default()
+        };
+        let mut session: Session = Session::default();
+        assert!(load_library("inside", &settings, &mut session).is_some());
+        assert!(fails(|| load_library("outer", &settings, &mut Session::default())));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn limits_name_the_macro() {
+        let settings: Settings = Settings {
+            max_steps: Some(2),
+            max_depth: Some(1),
+            ..Settings::default()
+        };
+
+        let mut session: Session = Session::default();
+        session.step("a", Some('\\'), &settings);
+        session.step("b", None, &settings);
+        assert!(fails(|| session.step("c", Some('\\'), &settings)));
+
+        let mut session: Session = Session::default();
+        session.enter_nested(&Input::default(), "", &settings);
+        assert!(fails(|| session.enter_nested(&Input::default(), "", &settings)));
+
+        let settings: Settings = Settings {
+            max_output: Some(4),
+            max_input: Some(4),
+            ..Settings::default()
+        };
+        let session: Session = Session::default();
+        assert!(!fails(|| session.check_sizes(&Input::from_string(String::from("abcd")), "abcd", &settings)));
+        assert!(fails(|| session.check_sizes(&Input::from_string(String::from("abcde")), "", &settings)));
+        assert!(fails(|| session.check_sizes(&Input::default(), "abcde", &settings)));
     }
 
-    state_machine(&mut input, &mut output, &mut macro_hash);
+    fn parse(args: &[&str]) -> (Settings, Vec<InputArg>) {
+        let args: Vec<String> = ["rust_macro_processor"].iter().chain(args).map(|arg| arg.to_string()).collect();
+        let mut settings: Settings = Settings::default();
+        let inputs: Vec<InputArg> = parse_args(&args, &mut settings);
+        (settings, inputs)
+    }
+
+    #[test]
+    fn parse_args_inputs() {
+        assert_eq!(parse(&[]).1, vec![InputArg::Stdin]);
+        assert_eq!(
+            parse(&["a.tex", "-", "-e", "\\x", "--eval=y", "b.tex"]).1,
+            vec![
+                InputArg::File(String::from("a.tex")),
+                InputArg::Stdin,
+                InputArg::Text(String::from("\\x")),
+                InputArg::Text(String::from("y")),
+                InputArg::File(String::from("b.tex")),
+            ]
+        );
+
+        // after "--" everything is a file, also "-" and names starting with a dash
+        assert_eq!(
+            parse(&["--", "-", "--help", "-o"]).1,
+            vec![
+                InputArg::File(String::from("-")),
+                InputArg::File(String::from("--help")),
+                InputArg::File(String::from("-o")),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_args_options() {
+        let (settings, inputs): (Settings, Vec<InputArg>) = parse(&[
+            "-oout.txt",
+            "-I",
+            "inc",
+            "--include-dir=lib",
+            "-Dname=a=b",
+            "--define",
+            "empty",
+            "-Ugone",
+            "--max-steps=10",
+            "--max-depth",
+            "3",
+            "--newline=crlf",
+            "--catcode",
+            "==comment",
+            "--input-encoding=latin-1",
+            "--dump-macros",
+            "in.tex",
+        ]);
+
+        assert_eq!(inputs, vec![InputArg::File(String::from("in.tex"))]);
+        assert_eq!(settings.output, Some(PathBuf::from("out.txt")));
+        assert_eq!(settings.include_dirs, vec![PathBuf::from("inc"), PathBuf::from("lib")]);
+        assert_eq!(
+            settings.defines,
+            vec![
+                (String::from("name"), Some(String::from("a=b"))),
+                (String::from("empty"), Some(String::new())),
+                (String::from("gone"), None),
+            ]
+        );
+        assert_eq!(settings.max_steps, Some(10));
+        assert_eq!(settings.max_depth, Some(3));
+        assert!(settings.newline == Newline::CrLf);
+        assert!(settings.catcodes.get('=') == Catcode::Comment);
+        assert!(settings.input_encoding == Encoding::Latin1);
+        assert!(settings.dump_macros);
+        assert_eq!(settings.max_include_size, None);
+
+        // a sandbox brings a default size limit for included files
+        let root: PathBuf = temp_dir("parse_args");
+        let (settings, _): (Settings, Vec<InputArg>) = parse(&["--sandbox", root.to_str().unwrap()]);
+        assert_eq!(settings.sandbox_root, Some(root));
+        assert_eq!(settings.max_include_size, Some(DEFAULT_SANDBOX_MAX_SIZE));
+    }
 
-    print!("{}", output);
+    // expands the text the way a file given on the command line is, returns the output
+    fn expand(text: &str, settings: &Settings, session: &mut Session) -> String {
+        let mut input: Input = Input::default();
+        input.push_source(Source::new(Box::new(io::Cursor::new(text.to_string().into_bytes())), "<test>", None, Encoding::Utf8));
+        let mut output: Vec<u8> = Vec::new();
+        let mut macro_table: MacroTable = MacroTable::default();
+        let mut catcodes: Catcodes = settings.catcodes.clone();
+
+        session.tracing = settings.trace;
+        session.limited = has_limits(settings);
+        state_machine(&mut input, &mut output, &mut macro_table, &mut catcodes, settings, session);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn limit_errors_name_the_innermost_call() {
+        let settings: Settings = Settings {
+            max_output: Some(1000),
+            ..Settings::default()
+        };
+
+        // the call has returned long before the text after it exceeds the limit
+        let text: String = format!("\\def{{a}}{{x}}\\a{{}}{}", "y".repeat(5000));
+        let mut session: Session = Session::default();
+        assert!(fails(|| expand(&text, &settings, &mut session)));
+        assert_eq!(session.context(), "outside any macro");
+
+        // while here the text is produced by the calls themselves
+        let text: String = format!("\\def{{b}}{{{}}}\\def{{a}}{{\\b{{}}}}\\a{{}}", "y".repeat(5000));
+        let mut session: Session = Session::default();
+        assert!(fails(|| expand(&text, &settings, &mut session)));
+        assert_eq!(session.context(), "while expanding \\b");
+
+        // the call is named with the escape character it was made with
+        let text: String = format!("\\catcode{{!}}{{escape}}!def{{b}}{{{}}}!b{{}}", "y".repeat(5000));
+        let mut session: Session = Session::default();
+        assert!(fails(|| expand(&text, &settings, &mut session)));
+        assert_eq!(session.context(), "while expanding !b");
+
+        let settings: Settings = Settings {
+            max_depth: Some(1),
+            ..Settings::default()
+        };
+        let mut session: Session = Session::default();
+        assert_eq!(expand("\\def{a}{x}\\expandafter{y}{\\a{}}", &settings, &mut session), "yx");
+        assert!(fails(|| expand("\\def{a}{x}\\expandafter{y}{\\expandafter{z}{\\a{}}}", &settings, &mut session)));
+        assert_eq!(session.context(), "while expanding \\expandafter");
+    }
+
+    #[test]
+    fn catcode_of_the_comment_character() {
+        let settings: Settings = Settings::default();
+
+        assert_eq!(expand("\\catcode{%}{other}50% off", &settings, &mut Session::default()), "50% off");
+        assert_eq!(expand("\\catcode{\\%}{other}50% off", &settings, &mut Session::default()), "50% off");
+        assert_eq!(expand("50% off\n", &settings, &mut Session::default()), "50");
+    }
+
+    #[test]
+    fn pure_macros_expand_like_others() {
+        let settings: Settings = Settings::default();
+        let cases: [(&str, &str); 5] = [
+            // the expansion reads past the arguments of the pure call
+            ("\\def{g}{G#}\\def{f}{\\g}\\pure{f}\\f{}{x}\\f{}{y}", "GxGy"),
+            ("\\def{g}{G#}\\def{f}{a\\g}\\pure{f}\\f{}{x}b", "aGxb"),
+            ("\\def{gh}{H}\\def{f}{\\g}\\pure{f}\\f{}h{}", "H"),
+            // cached results are dropped when a dependency is redefined or undefined
+            ("\\def{g}{1}\\def{f}{\\g{}#}\\pure{f}\\f{a}\\f{a}\\undef{g}\\def{g}{2}\\f{a}", "1a1a2a"),
+            ("\\def{g}{}\\def{f}{\\ifdef{g}{yes}{no}}\\pure{f}\\f{}\\f{}\\undef{g}\\f{}", "yesyesno"),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(expand(text, &settings, &mut Session::default()), expected, "{}", text);
+            assert_eq!(expand(&text.replace("\\pure{f}", ""), &settings, &mut Session::default()), expected, "{}", text);
+        }
+    }
 }
@@ -0,0 +1,211 @@
+// memoization of pure macro expansions
+//
+// a call of a macro declared with \pure is expanded on its own, like the AFTER
+// argument of \expandafter, and the result is cached by macro, definition and
+// argument. every macro the expansion looked at is remembered together with its
+// generation, so a later \def or \undef of any of them invalidates the entry
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::macros::{MacroTable, Symbol};
+
+/* ################################# Define Structs ################################# */
+
+#[derive(PartialEq, Eq, Hash)]
+pub struct MemoKey {
+    symbol: Symbol,
+    generation: u64,
+    argument: String,
+}
+
+struct MemoEntry {
+    result: Rc<str>,
+    dependencies: HashMap<String, u64>,
+}
+
+// collects the dependencies of one expansion that is in progress
+struct Recorder {
+    dependencies: HashMap<String, u64>,
+    cacheable: bool,
+}
+
+#[derive(Default)]
+pub struct Memo {
+    cache: HashMap<MemoKey, MemoEntry>,
+    // one per memoized expansion in progress, innermost last
+    recorders: Vec<Recorder>,
+}
+
+/* ################################# Memo Functions ################################# */
+
+impl MemoKey {
+    pub fn new(symbol: Symbol, macro_table: &MacroTable, argument: &str) -> MemoKey {
+        MemoKey {
+            symbol,
+            generation: macro_table.symbol_generation(symbol),
+            argument: argument.to_string(),
+        }
+    }
+}
+
+impl Memo {
+    // returns the cached result if none of its dependencies changed since
+    pub fn get(&mut self, key: &MemoKey, macro_table: &MacroTable) -> Option<Rc<str>> {
+        let entry: &MemoEntry = self.cache.get(key)?;
+
+        let valid: bool = entry.dependencies.iter().all(|(name, generation)| macro_table.generation(name) == *generation);
+        if !valid {
+            self.cache.remove(key);
+            return None;
+        }
+
+        // an enclosing expansion depends on whatever the cached one depended on
+        let result: Rc<str> = Rc::clone(&entry.result);
+        if let Some(recorder) = self.recorders.last_mut() {
+            for (name, generation) in &entry.dependencies {
+                recorder.dependencies.entry(name.clone()).or_insert(*generation);
+            }
+        }
+
+        Some(result)
+    }
+
+    // remembers that the expansion in progress looked at a macro
+    pub fn record(&mut self, name: &str, macro_table: &MacroTable) {
+        if let Some(recorder) = self.recorders.last_mut() {
+            if !recorder.dependencies.contains_key(name) {
+                recorder.dependencies.insert(name.to_string(), macro_table.generation(name));
+            }
+        }
+    }
+
+    // expansions that define macros or read files are never cached
+    pub fn side_effect(&mut self) {
+        for recorder in &mut self.recorders {
+            recorder.cacheable = false;
+        }
+    }
+
+    // drops every cached result
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+
+    // forgets the expansions in progress after an error ended them
+    pub fn abort(&mut self) {
+        self.recorders.clear();
+    }
+
+    pub fn begin(&mut self) {
+        self.recorders.push(Recorder {
+            dependencies: HashMap::new(),
+            cacheable: true,
+        });
+    }
+
+    // ends the innermost expansion without caching it, the enclosing one still depends on what it looked at
+    pub fn cancel(&mut self) {
+        let recorder: Recorder = self.recorders.pop().unwrap();
+
+        if let Some(parent) = self.recorders.last_mut() {
+            for (name, generation) in recorder.dependencies {
+                parent.dependencies.entry(name).or_insert(generation);
+            }
+        }
+    }
+
+    pub fn finish(&mut self, key: MemoKey, result: &str) {
+        let recorder: Recorder = self.recorders.pop().unwrap();
+
+        if let Some(parent) = self.recorders.last_mut() {
+            for (name, generation) in &recorder.dependencies {
+                parent.dependencies.entry(name.clone()).or_insert(*generation);
+            }
+        }
+
+        if recorder.cacheable {
+            self.cache.insert(key, MemoEntry {
+                result: Rc::from(result),
+                dependencies: recorder.dependencies,
+            });
+        }
+    }
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catcode::Catcodes;
+    use crate::macros::MacroDef;
+
+    // caches the expansion of \f{x} as depending on \g
+    fn cache_f(memo: &mut Memo, macro_table: &MacroTable) -> MemoKey {
+        let f: Symbol = macro_table.lookup("f").unwrap();
+        memo.begin();
+        memo.record("g", macro_table);
+        memo.finish(MemoKey::new(f, macro_table, "x"), "G");
+        MemoKey::new(f, macro_table, "x")
+    }
+
+    fn table() -> MacroTable {
+        let catcodes: Catcodes = Catcodes::default();
+        let mut macro_table: MacroTable = MacroTable::default();
+        macro_table.define("f", MacroDef::new("\\g{}", &catcodes));
+        macro_table.define("g", MacroDef::new("G", &catcodes));
+        macro_table
+    }
+
+    #[test]
+    fn dependencies_invalidate_entries() {
+        let catcodes: Catcodes = Catcodes::default();
+        let mut memo: Memo = Memo::default();
+        let mut macro_table: MacroTable = table();
+
+        let key: MemoKey = cache_f(&mut memo, &macro_table);
+        assert_eq!(memo.get(&key, &macro_table).as_deref(), Some("G"));
+
+        // redefining a dependency drops the entry
+        macro_table.undefine("g");
+        macro_table.define("g", MacroDef::new("H", &catcodes));
+        assert_eq!(memo.get(&key, &macro_table), None);
+
+        // and so does undefining it
+        let key: MemoKey = cache_f(&mut memo, &macro_table);
+        assert_eq!(memo.get(&key, &macro_table).as_deref(), Some("G"));
+        macro_table.undefine("g");
+        assert_eq!(memo.get(&key, &macro_table), None);
+
+        // redefining the macro itself changes the key
+        let mut macro_table: MacroTable = table();
+        let key: MemoKey = cache_f(&mut memo, &macro_table);
+        macro_table.undefine("f");
+        macro_table.define("f", MacroDef::new("\\g{}", &catcodes));
+        let f: Symbol = macro_table.lookup("f").unwrap();
+        assert!(key != MemoKey::new(f, &macro_table, "x"));
+        assert_eq!(memo.get(&MemoKey::new(f, &macro_table, "x"), &macro_table), None);
+    }
+
+    #[test]
+    fn side_effects_and_cancelled_expansions_are_not_cached() {
+        let macro_table: MacroTable = table();
+        let f: Symbol = macro_table.lookup("f").unwrap();
+        let mut memo: Memo = Memo::default();
+
+        memo.begin();
+        memo.side_effect();
+        memo.finish(MemoKey::new(f, &macro_table, "x"), "G");
+        assert_eq!(memo.get(&MemoKey::new(f, &macro_table, "x"), &macro_table), None);
+
+        // a cancelled expansion hands its dependencies to the enclosing one
+        memo.begin();
+        memo.begin();
+        memo.record("g", &macro_table);
+        memo.cancel();
+        memo.finish(MemoKey::new(f, &macro_table, "y"), "G");
+        assert_eq!(memo.get(&MemoKey::new(f, &macro_table, "x"), &macro_table), None);
+        assert_eq!(memo.cache[&MemoKey::new(f, &macro_table, "y")].dependencies.len(), 1);
+    }
+}
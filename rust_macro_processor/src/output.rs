@@ -0,0 +1,190 @@
+// spooling of the expanded output
+//
+// by default the output is streamed to stdout while it is produced, a spool keeps
+// it back until the expansion succeeded so that errors never leave partial output
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::scan::find_any;
+
+/* ################################# Define Structs ################################# */
+
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum Spool {
+    #[default]
+    Off,
+    Memory,
+    File,
+}
+
+// line endings written to the output
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum Newline {
+    #[default]
+    Preserve,
+    Lf,
+    CrLf,
+}
+
+// translates every line ending (CR, LF or CRLF) written through it
+pub struct NewlineWriter<W: Write> {
+    inner: W,
+    newline: Newline,
+    // the last byte written was a CR, so a following LF belongs to it
+    after_cr: bool,
+}
+
+// a file that is removed again when dropped, including when an error unwinds,
+// unless it was persisted under its final name
+pub struct TempFile {
+    path: PathBuf,
+    file: File,
+    persisted: bool,
+}
+
+/* ################################# Temp File Functions ################################# */
+
+impl TempFile {
+    pub fn create(dir: &Path) -> io::Result<TempFile> {
+        let nanos: u128 = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos());
+
+        let mut attempt: u32 = 0;
+        loop {
+            let path: PathBuf = dir.join(format!(".rust_macro_processor-{}-{}-{}.tmp", process::id(), nanos, attempt));
+
+            match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    return Ok(TempFile {
+                        path,
+                        file,
+                        persisted: false,
+                    })
+                }
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    // copies everything written so far to the given writer
+    pub fn copy_to(&mut self, writer: &mut dyn io::Write) -> io::Result<u64> {
+        self.file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut self.file, writer)
+    }
+
+    // atomically replaces the file at path, which must be on the same file system,
+    // an existing file there passes its permissions on
+    pub fn persist(mut self, path: &Path) -> io::Result<()> {
+        self.file.sync_all()?;
+
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&self.path, metadata.permissions())?;
+        }
+
+        fs::rename(&self.path, path)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/* ################################# Newline Writer Functions ################################# */
+
+impl<W: Write> NewlineWriter<W> {
+    pub fn new(inner: W, newline: Newline) -> NewlineWriter<W> {
+        NewlineWriter {
+            inner,
+            newline,
+            after_cr: false,
+        }
+    }
+}
+
+impl<W: Write> Write for NewlineWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let ending: &[u8] = match self.newline {
+            Newline::Preserve => return self.inner.write(buf),
+            Newline::Lf => b"\n",
+            Newline::CrLf => b"\r\n",
+        };
+
+        let mut converted: Vec<u8> = Vec::with_capacity(buf.len() + buf.len() / 16);
+        let mut rest: &[u8] = buf;
+
+        while !rest.is_empty() {
+            let run: usize = find_any(rest, b"\r\n").unwrap_or(rest.len());
+            if run > 0 {
+                converted.extend_from_slice(&rest[..run]);
+                self.after_cr = false;
+            }
+
+            if run < rest.len() {
+                if rest[run] == b'\r' {
+                    converted.extend_from_slice(ending);
+                    self.after_cr = true;
+                } else {
+                    if !self.after_cr {
+                        converted.extend_from_slice(ending);
+                    }
+                    self.after_cr = false;
+                }
+                rest = &rest[run + 1..];
+            } else {
+                rest = &[];
+            }
+        }
+
+        self.inner.write_all(&converted)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate(newline: Newline, writes: &[&str]) -> String {
+        let mut writer: NewlineWriter<Vec<u8>> = NewlineWriter::new(Vec::new(), newline);
+        for text in writes {
+            writer.write_all(text.as_bytes()).unwrap();
+        }
+        String::from_utf8(writer.inner).unwrap()
+    }
+
+    #[test]
+    fn newlines() {
+        let text: &str = "a\nb\r\nc\rd\r\r\ne\n\n";
+
+        assert_eq!(translate(Newline::Preserve, &[text]), text);
+        assert_eq!(translate(Newline::Lf, &[text]), "a\nb\nc\nd\n\ne\n\n");
+        assert_eq!(translate(Newline::CrLf, &[text]), "a\r\nb\r\nc\r\nd\r\n\r\ne\r\n\r\n");
+    }
+
+    #[test]
+    fn crlf_split_across_writes() {
+        assert_eq!(translate(Newline::Lf, &["a\r", "\nb"]), "a\nb");
+        assert_eq!(translate(Newline::CrLf, &["a\r", "\n", "\n"]), "a\r\n\r\n");
+        assert_eq!(translate(Newline::Lf, &["a\r", "b\n"]), "a\nb\n");
+    }
+}
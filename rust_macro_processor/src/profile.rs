@@ -0,0 +1,216 @@
+// expansion profiler for --profile
+//
+// a call starts when its name is read and ends once the text it put in front of the
+// input (a macro body, an \if branch, an included file) has been read, so its time
+// includes the calls made by that text, which are its children on the call stack
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/* ################################# Define Structs ################################# */
+
+struct Call {
+    name: String,
+    // the nesting level of the input the call was read from
+    level: usize,
+    // the number of input chunks below the text the call returned, None while it has not returned yet
+    base: Option<usize>,
+    start: Instant,
+    // output written before the call, at its level, in bytes
+    start_output: usize,
+    // characters of the call's output already handed to the sink, see flushed
+    flushed_chars: usize,
+    children: Duration,
+}
+
+#[derive(Default)]
+struct Stats {
+    calls: u64,
+    chars: usize,
+    inclusive: Duration,
+    exclusive: Duration,
+}
+
+pub struct Profiler {
+    stack: Vec<Call>,
+    stats: HashMap<String, Stats>,
+    // exclusive time per call stack, for the collapsed stack export
+    stacks: HashMap<String, Duration>,
+    start: Instant,
+}
+
+/* ################################# Profiler Functions ################################# */
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            stack: Vec::new(),
+            stats: HashMap::new(),
+            stacks: HashMap::new(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn enter(&mut self, name: String, level: usize, output: usize) {
+        self.stack.push(Call {
+            name,
+            level,
+            base: None,
+            start: Instant::now(),
+            start_output: output,
+            flushed_chars: 0,
+            children: Duration::ZERO,
+        });
+    }
+
+    // the innermost call is done reading its arguments, chunks is the size of the input before its result is pushed
+    pub fn returned(&mut self, chunks: usize) {
+        if let Some(call) = self.stack.last_mut() {
+            call.base = Some(chunks);
+        }
+    }
+
+    // the nested expansion at level + 1 goes on at level, its output following the caller's output at the mark
+    pub fn join_caller(&mut self, chunks: usize, level: usize, mark: usize) {
+        if let Some(call) = self.stack.iter_mut().rev().find(|call| call.level == level) {
+            call.base.get_or_insert(chunks);
+        }
+        for call in self.stack.iter_mut().filter(|call| call.level > level) {
+            call.level = level;
+            call.start_output += mark;
+        }
+    }
+
+    // the outermost output is about to be handed to the sink, written is the number of bytes written before it,
+    // the characters the open calls have output so far are counted now as the text is gone afterwards
+    pub fn flushed(&mut self, output: &str, written: usize) {
+        let end: usize = written + output.len();
+        let mut counted: Option<(usize, usize)> = None;
+
+        // calls further in started later, so the text is counted from the end backwards
+        for call in self.stack.iter_mut().rev() {
+            if call.level != 0 || call.start_output >= end {
+                continue;
+            }
+
+            let start: usize = call.start_output.saturating_sub(written);
+            let chars: usize = match counted {
+                Some((counted_start, counted_chars)) => counted_chars + output[start..counted_start].chars().count(),
+                None => output[start..].chars().count(),
+            };
+            counted = Some((start, chars));
+
+            call.flushed_chars += chars;
+            call.start_output = end;
+        }
+    }
+
+    // ends the calls whose result has been read from the input at the given level, output is the
+    // output of that level not handed to the sink yet, which ends at the mark
+    pub fn leave(&mut self, chunks: usize, level: usize, output: &str, mark: usize) {
+        while let Some(call) = self.stack.last() {
+            if call.level != level || call.base.is_none_or(|base| chunks > base) {
+                break;
+            }
+
+            let call: Call = self.stack.pop().unwrap();
+            let inclusive: Duration = call.start.elapsed();
+            let exclusive: Duration = inclusive.saturating_sub(call.children);
+
+            if let Some(parent) = self.stack.last_mut() {
+                parent.children += inclusive;
+            }
+
+            let mut stack: String = String::new();
+            for caller in &self.stack {
+                stack.push_str(&caller.name);
+                stack.push(';');
+            }
+            stack.push_str(&call.name);
+            *self.stacks.entry(stack).or_default() += exclusive;
+
+            // a recursive call is already covered by the inclusive figures of its outermost instance
+            let recursive: bool = self.stack.iter().any(|caller| caller.name == call.name);
+            let stats: &mut Stats = self.stats.entry(call.name).or_default();
+            stats.calls += 1;
+            stats.exclusive += exclusive;
+
+            if !recursive {
+                stats.inclusive += inclusive;
+                let unflushed: usize = call.start_output.saturating_sub(mark - output.len());
+                stats.chars += call.flushed_chars + output.get(unflushed..).map_or(0, |text| text.chars().count());
+            }
+        }
+    }
+
+    // the summary table, sorted by exclusive time
+    pub fn report(&self) -> String {
+        let mut rows: Vec<(&String, &Stats)> = self.stats.iter().collect();
+        rows.sort_by(|a, b| b.1.exclusive.cmp(&a.1.exclusive).then_with(|| a.0.cmp(b.0)));
+
+        let mut report: String = String::new();
+        let _ = writeln!(report, "Expansion took {:.3} ms", millis(self.start.elapsed()));
+        let _ = writeln!(report, "{:>10} {:>12} {:>14} {:>14}  macro", "calls", "chars", "inclusive ms", "exclusive ms");
+
+        for (name, stats) in rows {
+            let _ = writeln!(report, "{:>10} {:>12} {:>14.3} {:>14.3}  {}", stats.calls, stats.chars, millis(stats.inclusive), millis(stats.exclusive), name);
+        }
+
+        report
+    }
+
+    // one "caller;callee microseconds" line per call stack, as flame graph tools read it
+    pub fn collapsed_stacks(&self) -> String {
+        let mut stacks: Vec<(&String, &Duration)> = self.stacks.iter().collect();
+        stacks.sort();
+
+        let mut collapsed: String = String::new();
+        for (stack, time) in stacks {
+            let _ = writeln!(collapsed, "{} {}", stack, time.as_micros());
+        }
+
+        collapsed
+    }
+}
+
+fn millis(time: Duration) -> f64 {
+    time.as_secs_f64() * 1000.0
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chars_are_characters() {
+        let mut profiler: Profiler = Profiler::new();
+
+        // \a outputs "é", calls \b which outputs "éé" across a flush of the outermost output, then "x"
+        profiler.enter(String::from("\\a"), 0, 3);
+        profiler.returned(1);
+        profiler.enter(String::from("\\b"), 0, 5);
+        profiler.returned(2);
+        profiler.flushed("abcéé", 0);
+        profiler.leave(2, 0, "é", 9);
+        profiler.leave(1, 0, "éx", 10);
+
+        assert_eq!(profiler.stats["\\b"].chars, 2);
+        assert_eq!(profiler.stats["\\a"].chars, 4);
+    }
+
+    #[test]
+    fn chars_of_nested_levels() {
+        let mut profiler: Profiler = Profiler::new();
+
+        // a nested level starts from its own empty output and is never flushed
+        profiler.enter(String::from("\\a"), 1, 0);
+        profiler.returned(0);
+        profiler.flushed("ééé", 0);
+        profiler.leave(0, 1, "ééé", 6);
+
+        assert_eq!(profiler.stats["\\a"].chars, 3);
+    }
+}
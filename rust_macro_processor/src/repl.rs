@@ -0,0 +1,247 @@
+// interactive mode for --repl
+//
+// every line read from the terminal is expanded on its own, against a macro table
+// and catcodes that persist between lines, an error only ends the current line
+
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::catcode::{Catcode, Catcodes};
+use crate::encoding::Encoding;
+use crate::input::Input;
+use crate::macros::{MacroDef, MacroTable};
+use crate::output::NewlineWriter;
+use crate::source::Source;
+use crate::{has_limits, include_file, is_macro_name, predefine, push_inputs, resolve_include, state_machine, InputArg, Session, Settings};
+
+/* ################################# Define Structs ################################# */
+
+const HELP: &str = "\
+Lines are expanded as they are entered, a line with unclosed groups continues on the next line.
+  :defs         list the defined macros
+  :show NAME    print the definition of NAME
+  :undef NAME   remove the definition of NAME
+  :load FILE    expand FILE like \\include{FILE}, keeping its definitions
+  :reset        forget every definition and catcode change
+  :help         print this help
+  :quit         leave, like the end of the input
+";
+
+struct Repl<'a> {
+    settings: &'a Settings,
+    macro_table: MacroTable,
+    catcodes: Catcodes,
+    session: Session,
+}
+
+/* ################################# REPL Functions ################################# */
+
+// the inputs given on the command line are expanded first
+pub fn repl(settings: &Settings, inputs: &[InputArg]) {
+    let mut repl: Repl = Repl::new(settings);
+
+    if !inputs.is_empty() {
+        repl.expand(|input, settings, _| push_inputs(input, inputs, settings));
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        let mut text: String = String::new();
+
+        // lines are joined until every group is closed
+        loop {
+            prompt(if text.is_empty() { "> " } else { "... " });
+
+            match lines.next() {
+                Some(Ok(line)) => {
+                    text.push_str(&line);
+                    text.push('\n');
+                }
+                _ => {
+                    // the end of the input leaves the loop like :quit
+                    eprintln!();
+                    return;
+                }
+            }
+
+            if open_groups(&text, &repl.catcodes) <= 0 {
+                break;
+            }
+        }
+
+        match text.trim_end().strip_prefix(':') {
+            Some(command) => {
+                if !repl.command(command.trim()) {
+                    return;
+                }
+            }
+            None => repl.expand(|input, _, _| input.push_source(Source::new(Box::new(io::Cursor::new(text.into_bytes())), "<repl>", None, Encoding::Utf8))),
+        }
+    }
+}
+
+impl<'a> Repl<'a> {
+    fn new(settings: &'a Settings) -> Repl<'a> {
+        let mut repl: Repl = Repl {
+            settings,
+            macro_table: MacroTable::default(),
+            catcodes: settings.catcodes.clone(),
+            session: Session::default(),
+        };
+
+        predefine(&mut repl.macro_table, settings, &repl.catcodes);
+        repl.session.tracing = settings.trace;
+        repl.session.limited = has_limits(settings);
+        repl
+    }
+
+    // expands the input pushed by the closure, the error message of a failed expansion has been printed by then
+    fn expand(&mut self, push: impl FnOnce(&mut Input, &Settings, &mut Session)) {
+        self.session.reset_limits();
+
+        let settings: &Settings = self.settings;
+        let macro_table: &mut MacroTable = &mut self.macro_table;
+        let catcodes: &mut Catcodes = &mut self.catcodes;
+        let session: &mut Session = &mut self.session;
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut input: Input = Input::default();
+            push(&mut input, settings, session);
+
+            let mut stdout: NewlineWriter<io::StdoutLock> = NewlineWriter::new(io::stdout().lock(), settings.newline);
+            state_machine(&mut input, &mut stdout, macro_table, catcodes, settings, session);
+        }));
+    }
+
+    // runs a meta-command, returns false to leave the REPL
+    fn command(&mut self, command: &str) -> bool {
+        let (name, argument): (&str, &str) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, ""),
+        };
+
+        match (name, argument) {
+            ("defs", "") => {
+                for (name, definition) in self.macro_table.iter() {
+                    println!("\\def{{{}}}{{{}}}", name, definition.body);
+                }
+            }
+            ("show", name) if is_macro_name(name) => match self.macro_table.lookup(name) {
+                Some(symbol) => {
+                    let definition: &MacroDef = self.macro_table.definition(symbol);
+                    let pure: &str = if definition.pure { " (pure)" } else { "" };
+                    println!("\\def{{{}}}{{{}}}{}", name, definition.body, pure);
+                }
+                None => eprintln!("Rust Macro Processor: Macro {} is not defined", name),
+            },
+            ("undef", name) if is_macro_name(name) => {
+                if !self.macro_table.undefine(name) {
+                    eprintln!("Rust Macro Processor: Cannot delete undefined macro");
+                }
+            }
+            ("load", file) if !file.is_empty() => {
+                // the file is read like an \include, so the sandbox and the size limit apply
+                self.expand(|input, settings, session| include_file(input, &resolve_include(file, settings), settings, session));
+            }
+            ("reset", "") => *self = Repl::new(self.settings),
+            ("help", "") => print!("{}", HELP),
+            ("quit", "") => return false,
+            _ => eprintln!("Rust Macro Processor: Unknown command :{}, try :help", command),
+        }
+
+        true
+    }
+}
+
+fn prompt(text: &str) {
+    eprint!("{}", text);
+    let _ = io::stderr().flush();
+}
+
+// the number of groups opened but not closed, escaped characters and comments do not count
+fn open_groups(text: &str, catcodes: &Catcodes) -> i64 {
+    let mut open: i64 = 0;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match catcodes.get(c) {
+            Catcode::Escape => {
+                chars.next();
+            }
+            Catcode::BeginGroup => open += 1,
+            Catcode::EndGroup => open -= 1,
+            Catcode::Comment => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    open
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn counts_open_groups() {
+        let catcodes: Catcodes = Catcodes::default();
+
+        assert_eq!(open_groups("plain text", &catcodes), 0);
+        assert_eq!(open_groups("\\def{a}{", &catcodes), 1);
+        assert_eq!(open_groups("\\def{a}{\\if{x}{", &catcodes), 2);
+        assert_eq!(open_groups("}}", &catcodes), -2);
+
+        // escaped braces and braces in comments are not counted
+        assert_eq!(open_groups("\\{\\def{a}{\\}", &catcodes), 1);
+        assert_eq!(open_groups("a{ % {{\nb", &catcodes), 1);
+        assert_eq!(open_groups("% }\n}", &catcodes), -1);
+    }
+
+    #[test]
+    fn follows_catcodes() {
+        let mut catcodes: Catcodes = Catcodes::default();
+        catcodes.set('[', Catcode::BeginGroup);
+        catcodes.set(']', Catcode::EndGroup);
+        catcodes.set('{', Catcode::Other);
+
+        assert_eq!(open_groups("\\def[a][{", &catcodes), 1);
+    }
+
+    #[test]
+    fn load_is_confined_to_the_sandbox() {
+        let dir: PathBuf = env::temp_dir().join(format!("rust_macro_processor_repl_{}", std::process::id()));
+        let root: PathBuf = dir.join("root");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("inner.tex"), "\\def{inner}{x}").unwrap();
+        fs::write(dir.join("outside.tex"), "\\def{outside}{x}").unwrap();
+
+        let settings: Settings = Settings {
+            sandbox_root: Some(fs::canonicalize(&root).unwrap()),
+            ..Settings::default()
+        };
+        let mut repl: Repl = Repl::new(&settings);
+
+        // the failed loads only end the command
+        assert!(repl.command("load inner.tex"));
+        assert!(repl.command("load ../outside.tex"));
+        assert!(repl.command(&format!("load {}", dir.join("outside.tex").display())));
+
+        assert!(repl.macro_table.lookup("inner").is_some());
+        assert!(repl.macro_table.lookup("outside").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+// byte scanning used by the plain text fast path
+
+/* ################################# Scanning Functions ################################# */
+
+const LOW_BITS: u64 = 0x0101_0101_0101_0101;
+const HIGH_BITS: u64 = 0x8080_8080_8080_8080;
+
+// finds the first occurrence of any of the needles, testing eight bytes per step
+pub fn find_any(haystack: &[u8], needles: &[u8]) -> Option<usize> {
+    let mut words = haystack.chunks_exact(8);
+    let mut offset: usize = 0;
+
+    for word in &mut words {
+        let word_bits: u64 = u64::from_le_bytes(word.try_into().unwrap());
+
+        // bytes equal to a needle become zero, which the subtraction borrow exposes
+        let found: bool = needles.iter().any(|&needle| {
+            let x: u64 = word_bits ^ (LOW_BITS * needle as u64);
+            x.wrapping_sub(LOW_BITS) & !x & HIGH_BITS != 0
+        });
+
+        if found {
+            return word.iter().position(|byte| needles.contains(byte)).map(|i| offset + i);
+        }
+        offset += 8;
+    }
+
+    words.remainder().iter().position(|byte| needles.contains(byte)).map(|i| offset + i)
+}
+
+// counts the occurrences of a byte, in runs short enough for a byte-sized counter so that the loop vectorizes
+pub fn count_byte(haystack: &[u8], needle: u8) -> usize {
+    haystack.chunks(255).map(|run| run.iter().fold(0u8, |count, &byte| count + (byte == needle) as u8) as usize).sum()
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_any_matches_a_plain_search() {
+        let needles: &[u8] = b"\\%~";
+
+        // every length up to a few words, with the needle in every position and in the remainder
+        for len in 0..40 {
+            for pos in 0..=len {
+                let mut haystack: Vec<u8> = vec![b'a'; len];
+                if pos < len {
+                    haystack[pos] = needles[pos % needles.len()];
+                }
+                let expected: Option<usize> = haystack.iter().position(|byte| needles.contains(byte));
+                assert_eq!(find_any(&haystack, needles), expected, "length {} position {}", len, pos);
+            }
+        }
+    }
+
+    #[test]
+    fn find_any_edge_cases() {
+        assert_eq!(find_any(b"", b"\\"), None);
+        assert_eq!(find_any(b"abcdefghijk", b""), None);
+        assert_eq!(find_any(b"abcdefgh\\\\", b"\\"), Some(8));
+        assert_eq!(find_any(b"ab%defgh\\", b"\\%"), Some(2));
+
+        // bytes next to a needle, or with the high bit set, are not mistaken for it
+        assert_eq!(find_any(b"[]]][[]]\\", b"\\"), Some(8));
+        assert_eq!(find_any("ééééé\\".as_bytes(), b"\\"), Some(10));
+        assert_eq!(find_any(&[0xFF; 16], &[0x7F]), None);
+        assert_eq!(find_any(&[0x00, 0x01, 0x80, 0xFF, 0x00, 0x01, 0x80, 0xFF, 0x7F], &[0x7F]), Some(8));
+        assert_eq!(find_any(&[0x01; 9], &[0x00]), None);
+    }
+
+    #[test]
+    fn count_byte_across_runs() {
+        let haystack: Vec<u8> = (0..1000).map(|i| if i % 3 == 0 { b'\n' } else { b'x' }).collect();
+        assert_eq!(count_byte(&haystack, b'\n'), 334);
+        assert_eq!(count_byte(&[b'\n'; 600], b'\n'), 600);
+        assert_eq!(count_byte(b"", b'\n'), 0);
+    }
+}
@@ -0,0 +1,577 @@
+// lazily read input files
+//
+// a source reads its file a block at a time and strips the comments while the
+// characters are taken, so only the current block of a file is kept in memory
+
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::mem;
+
+use crate::catcode::{Catcode, Catcodes};
+use crate::encoding::Encoding;
+use crate::scan::{count_byte, find_any};
+
+/* ################################# Define Structs ################################# */
+
+// bytes read from the file per block
+const BLOCK_SIZE: usize = 64 * 1024;
+
+// bytes of the previous block kept for looking back from the start of the next one
+const TAIL_SIZE: usize = 16;
+
+enum CommentState {
+    PlainText,
+    Escape,
+    StartComment,
+    // the comment ended with a CR, which may be the first half of a CRLF
+    CarriageReturn,
+    EndComment,
+}
+
+// line breaks counted so far, advanced as the read position moves
+#[derive(Clone, Copy, Default)]
+struct LineCount {
+    lines: usize,
+    // the index in the block the count has reached
+    counted: usize,
+    // the text counted ended with a CR, which may be the first half of a CRLF
+    after_return: bool,
+}
+
+pub struct Source {
+    reader: Box<dyn Read>,
+    // file name used in error messages
+    name: String,
+    // the decoded block and the read position in it
+    block: String,
+    pos: usize,
+    // the end of the previous block
+    tail: String,
+    // updated from position, which only borrows the source
+    line_count: Cell<LineCount>,
+    // bytes carried over to the next block, e.g. a character split by the block boundary
+    carry: Vec<u8>,
+    bytes_read: u64,
+    size_limit: Option<u64>,
+    // the byte order mark of the file may replace the encoding asked for
+    encoding: Encoding,
+    eof: bool,
+    state: CommentState,
+    // a character already run through the comment stripper but not yet taken
+    pending: Option<char>,
+}
+
+/* ################################# Source Functions ################################# */
+
+impl Source {
+    pub fn new(reader: Box<dyn Read>, name: &str, size_limit: Option<u64>, encoding: Encoding) -> Source {
+        Source {
+            reader,
+            name: name.to_string(),
+            block: String::new(),
+            pos: 0,
+            tail: String::new(),
+            line_count: Cell::new(LineCount::default()),
+            carry: Vec::new(),
+            bytes_read: 0,
+            size_limit,
+            encoding,
+            eof: false,
+            state: CommentState::PlainText,
+            pending: None,
+        }
+    }
+
+    // removes and returns the next character after comment stripping, None at the end of the file
+    pub fn pop(&mut self, catcodes: &Catcodes) -> Option<char> {
+        match self.pending.take() {
+            Some(c) => Some(c),
+            None => self.strip_next(catcodes),
+        }
+    }
+
+    pub fn peek(&mut self, catcodes: &Catcodes) -> Option<char> {
+        if self.pending.is_none() {
+            self.pending = self.strip_next(catcodes);
+        }
+        self.pending
+    }
+
+    // the file name and the line of the next character, for diagnostics
+    pub fn position(&self) -> (&str, usize) {
+        // a peeked line break has been read but not taken yet, it is the last character read
+        let end: usize = match self.pending {
+            Some(c @ ('\n' | '\r')) => self.pos - c.len_utf8(),
+            _ => self.pos,
+        };
+
+        (&self.name, self.count_lines(end) + 1)
+    }
+
+    // the character peeked at and the rest of the current block, for the debugger
+    pub fn unread(&self) -> (Option<char>, &str) {
+        (self.pending, &self.block[self.pos..])
+    }
+
+    // moves plain text up to the next stop byte to the output, returns the number of bytes moved,
+    // the stops have to include the escape and comment characters
+    pub fn pop_run(&mut self, output: &mut String, stops: &[u8]) -> usize {
+        let mut moved: usize = 0;
+
+        // a character peeked at (e.g. to see whether the input is empty) leads the run,
+        // an escaped comment character is left to the caller as well
+        if let Some(c) = self.pending {
+            if c.is_ascii() && stops.contains(&(c as u8)) {
+                return 0;
+            }
+
+            output.push(c);
+            self.pending = None;
+            moved += c.len_utf8();
+        }
+
+        if !matches!(self.state, CommentState::PlainText) {
+            return moved;
+        }
+
+        if self.pos == self.block.len() && !self.refill() {
+            return moved;
+        }
+
+        let text: &str = &self.block[self.pos..];
+        let run: usize = find_any(text.as_bytes(), stops).unwrap_or(text.len());
+
+        output.push_str(&text[..run]);
+        self.pos += run;
+
+        moved + run
+    }
+
+    fn strip_next(&mut self, catcodes: &Catcodes) -> Option<char> {
+        loop {
+            let c: char = self.next_raw()?;
+
+            match self.state {
+                CommentState::PlainText => {
+                    if catcodes.get(c) == Catcode::Escape {
+                        return Some(self.escape(c, catcodes));
+                    } else if catcodes.get(c) == Catcode::Comment && !self.is_catcode_target(c, catcodes) {
+                        self.state = CommentState::StartComment;
+                    } else {
+                        return Some(c);
+                    }
+                }
+                CommentState::Escape => {
+                    self.state = CommentState::PlainText;
+                    return Some(c);
+                }
+                CommentState::StartComment => {
+                    if c == '\n' {
+                        self.state = CommentState::EndComment;
+                    } else if c == '\r' {
+                        self.state = CommentState::CarriageReturn;
+                    }
+                }
+                CommentState::CarriageReturn => {
+                    self.state = CommentState::EndComment;
+                    if c != '\n' {
+                        if let Some(c) = self.end_comment(c, catcodes) {
+                            return Some(c);
+                        }
+                    }
+                }
+                CommentState::EndComment => {
+                    if let Some(c) = self.end_comment(c, catcodes) {
+                        return Some(c);
+                    }
+                }
+            }
+        }
+    }
+
+    // skips the indentation of the line after a comment, returns the first character that is kept
+    fn end_comment(&mut self, c: char, catcodes: &Catcodes) -> Option<char> {
+        if catcodes.get(c) == Catcode::Comment {
+            self.state = CommentState::StartComment;
+            None
+        } else if catcodes.get(c) == Catcode::Escape {
+            Some(self.escape(c, catcodes))
+        } else if c != '\t' && c != ' ' {
+            self.state = CommentState::PlainText;
+            Some(c)
+        } else {
+            None
+        }
+    }
+
+    // a comment character that is the whole CHAR argument of \catcode is kept, so that
+    // \catcode{%}{other} works without escaping it, c has just been read
+    fn is_catcode_target(&mut self, c: char, catcodes: &Catcodes) -> bool {
+        let end: usize = self.pos - c.len_utf8();
+        let start: usize = char_floor(&self.block, end.saturating_sub(TAIL_SIZE));
+
+        let mut before: String = if start == 0 { self.tail.clone() } else { String::new() };
+        before.push_str(&self.block[start..end]);
+
+        let mut chars = before.chars().rev();
+        if !chars.next().is_some_and(|c| catcodes.get(c) == Catcode::BeginGroup) {
+            return false;
+        }
+        if !"catcode".chars().rev().all(|name_char| chars.next() == Some(name_char)) {
+            return false;
+        }
+
+        // the escape character must not be escaped itself
+        let escapes: usize = chars.take_while(|&c| catcodes.get(c) == Catcode::Escape).count();
+        if escapes.is_multiple_of(2) {
+            return false;
+        }
+
+        if self.pos == self.block.len() {
+            self.refill();
+        }
+        self.block[self.pos..].chars().next().is_some_and(|c| catcodes.get(c) == Catcode::EndGroup)
+    }
+
+    // called after an escape character, an escaped comment character loses its escape
+    // while anything else following it is passed through without comment processing
+    fn escape(&mut self, escape: char, catcodes: &Catcodes) -> char {
+        // the escape may end the block, the character after it starts the next one
+        if self.pos == self.block.len() {
+            self.refill();
+        }
+
+        match self.block[self.pos..].chars().next() {
+            Some(c) if catcodes.get(c) == Catcode::Comment => {
+                self.pos += c.len_utf8();
+                self.state = CommentState::PlainText;
+                c
+            }
+            _ => {
+                self.state = CommentState::Escape;
+                escape
+            }
+        }
+    }
+
+    fn next_raw(&mut self) -> Option<char> {
+        if self.pos == self.block.len() && !self.refill() {
+            return None;
+        }
+
+        let c: char = self.block[self.pos..].chars().next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    // the line breaks before the index in the block, counting on from where the last call stopped
+    fn count_lines(&self, index: usize) -> usize {
+        let mut count: LineCount = self.line_count.get();
+        let text: &str = &self.block[count.counted..index];
+
+        if !text.is_empty() {
+            count.lines += count_line_breaks(text, count.after_return);
+            count.after_return = text.ends_with('\r');
+            count.counted = index;
+            self.line_count.set(count);
+        }
+
+        count.lines
+    }
+
+    // reads and decodes the next block, returns false at the end of the file
+    fn refill(&mut self) -> bool {
+        self.count_lines(self.block.len());
+        let mut count: LineCount = self.line_count.get();
+        count.counted = 0;
+        self.line_count.set(count);
+
+        // blocks can be shorter than the tail, then the old tail is carried along
+        let block_start: usize = char_floor(&self.block, self.block.len().saturating_sub(TAIL_SIZE));
+        if block_start > 0 {
+            self.tail.clear();
+        }
+        self.tail.push_str(&self.block[block_start..]);
+        self.tail.drain(..char_floor(&self.tail, self.tail.len().saturating_sub(TAIL_SIZE)));
+
+        self.block.clear();
+        self.pos = 0;
+
+        while self.block.is_empty() {
+            if self.eof {
+                return false;
+            }
+
+            let mut bytes: Vec<u8> = mem::take(&mut self.carry);
+            let start: usize = bytes.len();
+            // file offset of the first byte
+            let offset: u64 = self.bytes_read - start as u64;
+            bytes.resize(start + BLOCK_SIZE, 0);
+
+            let count: usize = loop {
+                match self.reader.read(&mut bytes[start..]) {
+                    Ok(count) => break count,
+                    Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => {
+                        die!("Unable to read file {}", self.name);
+                    }
+                }
+            };
+            bytes.truncate(start + count);
+
+            self.bytes_read += count as u64;
+            if let Some(limit) = self.size_limit {
+                if self.bytes_read > limit {
+                    die!("Included file {} exceeds the size limit of {} bytes", self.name, limit);
+                }
+            }
+
+            if count == 0 {
+                self.eof = true;
+            }
+
+            self.decode(bytes, offset);
+        }
+
+        true
+    }
+
+    // appends the decoded text of the bytes to the block and carries the rest over
+    fn decode(&mut self, bytes: Vec<u8>, offset: u64) {
+        let mut begin: usize = 0;
+
+        if offset == 0 {
+            match self.encoding.detect(&bytes, self.eof) {
+                Some((encoding, mark)) => {
+                    self.encoding = encoding;
+                    begin = mark;
+                }
+                None => {
+                    self.carry = bytes;
+                    return;
+                }
+            }
+        }
+
+        let end: usize = match self.encoding.decode(&bytes[begin..], self.eof, &mut self.block) {
+            Ok(used) => begin + used,
+            Err(invalid) => {
+                die!("Invalid {} in file {} at byte {}", self.encoding.name(), self.name, offset + (begin + invalid) as u64);
+            }
+        };
+
+        self.carry = bytes[end..].to_vec();
+    }
+}
+
+// the start of the character the byte index falls into
+fn char_floor(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+// counts LF, CRLF and lone CR line endings, after_return tells whether the text before ended with a CR
+fn count_line_breaks(text: &str, after_return: bool) -> usize {
+    let bytes: &[u8] = text.as_bytes();
+    let line_feeds: usize = count_byte(bytes, b'\n');
+    let returns: usize = count_byte(bytes, b'\r');
+
+    if returns == 0 && !after_return {
+        return line_feeds;
+    }
+
+    // the LF of a CRLF is counted with its CR, which may have ended the text before
+    let pairs: usize = bytes.windows(2).filter(|pair| pair == b"\r\n").count() + usize::from(after_return && bytes.first() == Some(&b'\n'));
+    returns + line_feeds - pairs
+}
+
+/* ################################# Tests ################################# */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hands out its bytes a few at a time, so that every split point becomes a block boundary
+    struct Trickle {
+        bytes: Vec<u8>,
+        pos: usize,
+        step: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count: usize = self.step.min(buf.len()).min(self.bytes.len() - self.pos);
+            buf[..count].copy_from_slice(&self.bytes[self.pos..self.pos + count]);
+            self.pos += count;
+            Ok(count)
+        }
+    }
+
+    fn strip(text: &str, step: usize) -> String {
+        strip_bytes(text.as_bytes(), step, Encoding::Utf8, &Catcodes::default())
+    }
+
+    // strips the bytes read in blocks of the given size, taking plain text runs where possible
+    fn strip_bytes(bytes: &[u8], step: usize, encoding: Encoding, catcodes: &Catcodes) -> String {
+        let reader: Trickle = Trickle {
+            bytes: bytes.to_vec(),
+            pos: 0,
+            step,
+        };
+        let mut source: Source = Source::new(Box::new(reader), "test", None, encoding);
+        let mut output: String = String::new();
+
+        loop {
+            if source.pop_run(&mut output, catcodes.source_stops()) > 0 {
+                continue;
+            }
+            match source.pop(catcodes) {
+                Some(c) => output.push(c),
+                None => return output,
+            }
+        }
+    }
+
+    #[test]
+    fn comments() {
+        let cases: [(&str, &str); 7] = [
+            ("a%comment\nb", "ab"),
+            ("a%comment\n  \t b", "ab"),
+            ("a%one\n%two\nb", "ab"),
+            ("a\\%b", "a%b"),
+            ("a\\\\%comment\nb", "a\\\\b"),
+            ("a%comment\n\\b", "a\\b"),
+            ("%comment", ""),
+        ];
+
+        for (text, expected) in cases {
+            for step in [1, 2, 3, BLOCK_SIZE] {
+                assert_eq!(strip(text, step), expected, "{:?} in blocks of {}", text, step);
+            }
+        }
+    }
+
+    #[test]
+    fn percent_at_block_edge() {
+        // with a block size of 4 the comment starts and ends exactly on block boundaries
+        assert_eq!(strip("abc%def\nghij", 4), "abcghij");
+        assert_eq!(strip("abc\\%defg", 4), "abc%defg");
+        assert_eq!(strip("abcd%efg\nhij", 4), "abcdhij");
+    }
+
+    #[test]
+    fn characters_split_across_blocks() {
+        assert_eq!(strip("éa€b\\é", 1), "éa€b\\é");
+        assert_eq!(strip("a%é€\nb", 1), "ab");
+    }
+
+    #[test]
+    fn byte_order_mark_only_at_the_start() {
+        let little: Vec<u8> = vec![0xFF, 0xFE, b'a', 0x00, 0xFF, 0xFE, b'%', 0x00, b'\n', 0x00, b'b', 0x00];
+        let big: Vec<u8> = vec![0xFE, 0xFF, 0x00, b'a', 0xFE, 0xFF];
+
+        // the mark is dropped and switches the encoding, a later one is an ordinary character
+        for step in [1, 2, 3, BLOCK_SIZE] {
+            assert_eq!(strip_bytes(&little, step, Encoding::Utf8, &Catcodes::default()), "a\u{FEFF}b", "blocks of {}", step);
+            assert_eq!(strip_bytes(&big, step, Encoding::Utf16, &Catcodes::default()), "a\u{FEFF}", "blocks of {}", step);
+        }
+
+        // a UTF-8 mark is dropped too, but only at the start of the file
+        let utf8: &[u8] = "\u{FEFF}a\u{FEFF}".as_bytes();
+        for step in [1, 2, BLOCK_SIZE] {
+            assert_eq!(strip_bytes(utf8, step, Encoding::Utf8, &Catcodes::default()), "a\u{FEFF}", "blocks of {}", step);
+        }
+
+        // text without a mark keeps the encoding asked for
+        assert_eq!(strip_bytes(&[0x00, b'a'], 1, Encoding::Utf16, &Catcodes::default()), "a");
+        assert_eq!(strip_bytes(b"a", 1, Encoding::Utf8, &Catcodes::default()), "a");
+    }
+
+    #[test]
+    fn line_endings_after_comments() {
+        let cases: [(&str, &str); 6] = [
+            ("a%comment\r\nb", "ab"),
+            ("a%comment\rb", "ab"),
+            ("a%comment\r\r\nb", "a\r\nb"),
+            ("a%comment\r  b", "ab"),
+            ("a%one\r%two\r\n\\b", "a\\b"),
+            ("a\r\nb\rc", "a\r\nb\rc"),
+        ];
+
+        for (text, expected) in cases {
+            for step in [1, 2, 3, BLOCK_SIZE] {
+                assert_eq!(strip(text, step), expected, "{:?} in blocks of {}", text, step);
+            }
+        }
+    }
+
+    #[test]
+    fn crlf_split_across_blocks() {
+        // the CR ends the first block and the LF starts the next one
+        assert_eq!(strip("ab%c\r\nde", 5), "abde");
+        assert_eq!(strip("ab%c\r\n  de", 5), "abde");
+        assert_eq!(strip("ab%c\r\r\nde", 6), "ab\r\nde");
+    }
+
+    #[test]
+    fn changed_catcodes() {
+        let mut catcodes: Catcodes = Catcodes::default();
+        catcodes.set('%', Catcode::Other);
+        catcodes.set(';', Catcode::Comment);
+        catcodes.set('\\', Catcode::Other);
+        catcodes.set('!', Catcode::Escape);
+
+        for step in [1, 2, BLOCK_SIZE] {
+            let stripped: String = strip_bytes(b"50% \\a;comment\n  b!;c", step, Encoding::Utf8, &catcodes);
+            assert_eq!(stripped, "50% \\ab;c", "blocks of {}", step);
+        }
+    }
+
+    #[test]
+    fn comment_character_as_catcode_target() {
+        let cases: [(&str, &str); 6] = [
+            ("\\catcode{%}{other}", "\\catcode{%}{other}"),
+            ("\\catcode{\\%}{other}", "\\catcode{%}{other}"),
+            // anywhere else the comment character still starts a comment
+            ("\\catcode{%x}\ny", "\\catcode{y"),
+            ("catcode{%}\ny", "catcode{y"),
+            ("\\\\catcode{%}\ny", "\\\\catcode{y"),
+            ("\\\\\\catcode{%}", "\\\\\\catcode{%}"),
+        ];
+
+        for (text, expected) in cases {
+            for step in [1, 2, 3, 5, BLOCK_SIZE] {
+                assert_eq!(strip(text, step), expected, "{:?} in blocks of {}", text, step);
+            }
+        }
+    }
+
+    #[test]
+    fn line_positions() {
+        let text: &str = "a\nb\r\nc\rd\r\r\ne%x\r\nf";
+        // the line of every character, a CR or LF starts the next line once it has been taken
+        let expected: [usize; 13] = [1, 1, 2, 2, 3, 3, 3, 4, 4, 5, 6, 6, 7];
+
+        for step in [1, 2, 3, 5, BLOCK_SIZE] {
+            let reader: Trickle = Trickle {
+                bytes: text.as_bytes().to_vec(),
+                pos: 0,
+                step,
+            };
+            let mut source: Source = Source::new(Box::new(reader), "test", None, Encoding::Utf8);
+            let catcodes: Catcodes = Catcodes::default();
+            let mut lines: Vec<usize> = Vec::new();
+
+            // the line is taken with the character peeked at, a comment before it has been skipped then
+            while source.peek(&catcodes).is_some() {
+                lines.push(source.position().1);
+                source.pop(&catcodes);
+            }
+
+            assert_eq!(lines, expected, "in blocks of {}", step);
+            assert_eq!(source.position().1, 7);
+        }
+    }
+}